@@ -0,0 +1,114 @@
+use brig_common::agent::Operation;
+
+/// Properties brig sets, with the values it sets them to
+const SETTABLE_PROPERTIES: [(&str, &[&str]); 2] = [
+    ("readonly", &["on", "off"]),
+    ("canmount", &["on", "off", "noauto"]),
+];
+
+/// Pool properties brig reads
+const POOL_PROPERTIES: [&str; 5] = ["allocated", "capacity", "health", "name", "size"];
+
+/// What the agent lets brig do on this host
+pub struct Policy {
+    /// pools brig may touch, every filesystem and snapshot must be in one
+    pub pools: Vec<String>,
+    /// also run switch hooks, which are arbitrary shell commands
+    pub allow_hooks: bool,
+}
+
+/// Characters zfs allows in a name component, without `%`, `,` and the like
+/// that zfs treats as ranges or lists
+fn is_component(component: &str) -> bool {
+    !component.is_empty()
+        && !component.starts_with('-')
+        && component != "."
+        && component != ".."
+        && component
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-.:".contains(c))
+}
+
+impl Policy {
+    /// `pool` or a filesystem below it, in one of the allowed pools
+    fn is_filesystem(&self, name: &str) -> bool {
+        let pool = name.split('/').next().unwrap_or_default();
+        self.pools.iter().any(|allowed| allowed == pool) && name.split('/').all(is_component)
+    }
+
+    /// A single `filesystem@name`, never a range or a list of snapshots
+    fn is_snapshot(&self, name: &str) -> bool {
+        name.split_once('@').is_some_and(|(filesystem, snapshot)| {
+            self.is_filesystem(filesystem) && is_component(snapshot)
+        })
+    }
+
+    /// Why the agent won't carry out `operation`, if it won't
+    pub fn check(&self, operation: &Operation) -> Result<(), String> {
+        let allowed = match operation {
+            // listing every pool is narrowed to the allowed ones by `command`
+            Operation::ListSnapshots { filesystem } => filesystem
+                .as_deref()
+                .is_none_or(|filesystem| self.is_filesystem(filesystem)),
+            Operation::GetProperty { target, property } => {
+                (self.is_filesystem(target) || self.is_snapshot(target)) && is_component(property)
+            }
+            Operation::SetProperty {
+                filesystem,
+                property,
+                value,
+            } => {
+                if !SETTABLE_PROPERTIES.iter().any(|(settable, values)| {
+                    settable == property && values.contains(&value.as_str())
+                }) {
+                    return Err(format!("brig doesn't set {}={}", property, value));
+                }
+                self.is_filesystem(filesystem)
+            }
+            Operation::PoolProperty { pool, property } => {
+                self.pools.contains(pool) && POOL_PROPERTIES.contains(&property.as_str())
+            }
+            Operation::Snapshot { snapshot }
+            | Operation::DestroySnapshot { snapshot }
+            | Operation::Diff { snapshot } => self.is_snapshot(snapshot),
+            Operation::EstimateSend { from, to } | Operation::Send { from, to } => {
+                self.is_snapshot(from) && self.is_snapshot(to)
+            }
+            // a forced receive into the pool's root would replace everything
+            Operation::Receive { filesystem } => {
+                self.is_filesystem(filesystem) && filesystem.contains('/')
+            }
+            Operation::Mount { filesystem, .. } => self.is_filesystem(filesystem),
+            Operation::MountpointInUse { mountpoint } => mountpoint.starts_with('/'),
+            Operation::ListPermissions { target } => self.is_filesystem(target),
+            Operation::ZfsVersion
+            | Operation::FuserVersion
+            | Operation::UserId
+            | Operation::UserGroups => true,
+            Operation::Hook { .. } => {
+                if !self.allow_hooks {
+                    return Err("hooks aren't allowed".to_owned());
+                }
+                true
+            }
+        };
+        if !allowed {
+            return Err(format!(
+                "the arguments aren't allowed, brig may only use the pools {}",
+                self.pools.join(", ")
+            ));
+        }
+        Ok(())
+    }
+
+    /// The command line for an operation `check` allowed. Snapshots of every
+    /// pool are only listed for the allowed pools.
+    pub fn command(&self, operation: &Operation) -> (&'static str, Vec<String>) {
+        let (program, mut args) = operation.command();
+        if let Operation::ListSnapshots { filesystem: None } = operation {
+            args.push("-r".to_owned());
+            args.extend(self.pools.iter().cloned());
+        }
+        (program, args)
+    }
+}
@@ -0,0 +1,21 @@
+/// Brig Agent, runs brig's zfs commands on this host
+#[derive(clap::Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// address to listen on. Streams aren't authenticated or encrypted, so
+    /// only listen on another address on a trusted network.
+    #[arg(short, long, default_value_t = format!("127.0.0.1:{}", brig_common::agent::DEFAULT_PORT))]
+    pub listen: String,
+    /// file holding the token shared with brig_server
+    #[arg(short, long)]
+    pub token_file: std::path::PathBuf,
+    /// a pool brig may manage datasets in, can be given more than once
+    #[arg(short, long = "pool", required = true)]
+    pub pools: Vec<String>,
+    /// also run switch hooks, which are arbitrary shell commands
+    #[arg(long)]
+    pub allow_hooks: bool,
+    /// connections handled at once, others wait until one closes
+    #[arg(long, default_value_t = 16)]
+    pub max_connections: usize,
+}
@@ -0,0 +1,201 @@
+mod allow;
+mod cli;
+
+use std::{io::ErrorKind, process::Stdio, sync::Arc, time::Duration};
+
+use allow::Policy;
+use anyhow::{Context, Result};
+use brig_common::{
+    agent::{self, AgentResponse, Challenge, Operation, Stream},
+    api::api::ErrorCode,
+};
+use clap::Parser;
+use cli::Cli;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    process::Command,
+    sync::Semaphore,
+};
+
+/// How long a peer has to answer the challenge before it's disconnected
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Settings {
+    token: Vec<u8>,
+    policy: Policy,
+}
+
+/// Runs a command to completion
+async fn output(program: &str, args: &[String]) -> AgentResponse {
+    match Command::new(program)
+        .args(args)
+        .kill_on_drop(true)
+        .output()
+        .await
+    {
+        Ok(output) => AgentResponse::Exited {
+            status: output.status.code(),
+            stdout: output.stdout,
+            stderr: output.stderr,
+        },
+        Err(e) => AgentResponse::Exited {
+            status: None,
+            stdout: vec![],
+            stderr: e.to_string().into_bytes(),
+        },
+    }
+}
+
+/// Starts a command and pumps its stdin or stdout through `conn` until the
+/// stream ends, then reports how it exited. The command is killed if brig
+/// goes away.
+async fn spawn(
+    conn: &mut TcpStream,
+    program: &str,
+    args: &[String],
+    stream: Stream,
+) -> std::io::Result<()> {
+    let piped = |on: bool| if on { Stdio::piped() } else { Stdio::null() };
+    let mut child = match Command::new(program)
+        .args(args)
+        .stdin(piped(stream == Stream::Stdin))
+        .stdout(piped(stream == Stream::Stdout))
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            let exited = AgentResponse::Exited {
+                status: None,
+                stdout: vec![],
+                stderr: e.to_string().into_bytes(),
+            };
+            return agent::write_message(conn, &exited).await;
+        }
+    };
+    agent::write_message(conn, &AgentResponse::Started).await?;
+
+    if let Some(mut stdout) = child.stdout.take() {
+        let mut buffer = [0u8; 65536];
+        loop {
+            let n = stdout.read(&mut buffer).await?;
+            agent::write_frame(conn, &buffer[..n]).await?;
+            if n == 0 {
+                break;
+            }
+        }
+    }
+    let mut stopped_reading = false;
+    if let Some(mut stdin) = child.stdin.take() {
+        loop {
+            let data = agent::read_frame(conn).await?;
+            if data.is_empty() {
+                let _ = stdin.shutdown().await;
+                break;
+            }
+            // the command stopped reading, most likely because it failed,
+            // which its exit status and stderr tell brig
+            if stdin.write_all(&data).await.is_err() {
+                stopped_reading = true;
+                break;
+            }
+        }
+    }
+
+    let output = child.wait_with_output().await?;
+    let exited = AgentResponse::Exited {
+        status: output.status.code(),
+        stdout: vec![],
+        stderr: output.stderr,
+    };
+    agent::write_message(conn, &exited).await?;
+    // brig reads the reply while it's still sending, closing with its frames
+    // unread would reset the connection before it got there
+    if stopped_reading {
+        let mut sink = tokio::io::sink();
+        let drain = tokio::io::copy(conn, &mut sink);
+        let _ = tokio::time::timeout(REQUEST_TIMEOUT, drain).await;
+    }
+    Ok(())
+}
+
+/// Sends a challenge and reads the signed operation that answers it
+async fn authenticate(conn: &mut TcpStream, token: &[u8]) -> std::io::Result<Option<Operation>> {
+    let nonce = agent::new_nonce()?;
+    let challenge = Challenge {
+        nonce: nonce.clone(),
+    };
+    agent::write_message(conn, &challenge).await?;
+    let signed = match agent::read_request(conn).await {
+        Ok(signed) => signed,
+        // brig checking that the agent is up
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let Some(operation) = agent::verify(token, &nonce, &signed) else {
+        println!("refused a request with a bad signature");
+        let refused = AgentResponse::Refused(ErrorCode::Unauthorized);
+        agent::write_message(conn, &refused).await?;
+        return Ok(None);
+    };
+    Ok(Some(operation))
+}
+
+async fn handle(mut conn: TcpStream, settings: Arc<Settings>) -> std::io::Result<()> {
+    let authenticated =
+        tokio::time::timeout(REQUEST_TIMEOUT, authenticate(&mut conn, &settings.token))
+            .await
+            .map_err(|_| std::io::Error::new(ErrorKind::TimedOut, "no request in time"))??;
+    let Some(operation) = authenticated else {
+        return Ok(());
+    };
+
+    if let Err(reason) = settings.policy.check(&operation) {
+        let command = operation.line();
+        println!("refused {}: {}", &command, reason);
+        let refused = AgentResponse::Refused(ErrorCode::AgentCommandNotAllowed { command });
+        return agent::write_message(&mut conn, &refused).await;
+    }
+
+    let (program, args) = settings.policy.command(&operation);
+    match operation.stream() {
+        Stream::None => {
+            let response = output(program, &args).await;
+            agent::write_message(&mut conn, &response).await
+        }
+        stream => spawn(&mut conn, program, &args, stream).await,
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Cli::parse();
+    let token = std::fs::read_to_string(&args.token_file)
+        .with_context(|| format!("unable to read token file {}", args.token_file.display()))?;
+    let settings = Arc::new(Settings {
+        token: agent::parse_token(&token)?,
+        policy: Policy {
+            pools: args.pools,
+            allow_hooks: args.allow_hooks,
+        },
+    });
+    let connections = Arc::new(Semaphore::new(args.max_connections));
+
+    let listener = TcpListener::bind(&args.listen)
+        .await
+        .with_context(|| format!("unable to listen on {}", &args.listen))?;
+    println!("listening on {}", &args.listen);
+    loop {
+        let permit = connections.clone().acquire_owned().await?;
+        let (conn, peer) = listener.accept().await?;
+        let settings = settings.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(conn, settings).await {
+                println!("connection from {} failed: {}", peer, e);
+            }
+            drop(permit);
+        });
+    }
+}
@@ -0,0 +1,323 @@
+//! The protocol between brig_server and brig_agent.
+//!
+//! Everything on a connection is a frame: a big-endian `u32` length followed
+//! by that many bytes. The agent opens with a `Challenge`, the server answers
+//! with one `SignedRequest` for an `Operation`, authenticated with the shared
+//! token, and the agent replies with an `AgentResponse`. A streaming operation
+//! is then followed by its stream as data frames ended by an empty frame, and
+//! by a final `Exited`.
+//!
+//! The token never crosses the wire, but only the request is authenticated
+//! and nothing is encrypted, so streams and replies can be read or altered on
+//! the way. Agents listen on loopback unless told otherwise and belong on a
+//! trusted network or behind a tunnel.
+
+use std::io;
+
+use ring::{hmac, rand::SecureRandom};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::api::api::ErrorCode;
+
+pub const DEFAULT_PORT: u16 = 7879;
+
+/// Larger frames are refused, stream data is sent in much smaller ones
+const MAX_FRAME: usize = 16 << 20;
+
+/// Larger requests are refused. They're read before the peer is
+/// authenticated, so this is kept small.
+const MAX_REQUEST_FRAME: usize = 16 << 10;
+
+#[derive(Serialize, Deserialize)]
+pub struct Challenge {
+    pub nonce: String,
+}
+
+/// Which of the command's pipes is streamed over the connection
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Stream {
+    None,
+    Stdin,
+    Stdout,
+}
+
+/// The properties `ListSnapshots` lists, in order
+pub const SNAPSHOT_PROPERTIES: &str = "name,guid,creation,used,referenced,written";
+
+/// Everything brig runs on a server. The SSH and local backends run the
+/// command line `command` builds, an agent checks the arguments against what
+/// it allows and builds the same command line itself, so it never runs a
+/// program brig names.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Operation {
+    /// `zfs list -t snapshot` of one filesystem newest first, or of every
+    /// pool
+    ListSnapshots {
+        filesystem: Option<String>,
+    },
+    GetProperty {
+        target: String,
+        property: String,
+    },
+    SetProperty {
+        filesystem: String,
+        property: String,
+        value: String,
+    },
+    /// `zpool list -o <property>`
+    PoolProperty {
+        pool: String,
+        property: String,
+    },
+    Snapshot {
+        snapshot: String,
+    },
+    DestroySnapshot {
+        snapshot: String,
+    },
+    /// `zfs send -n -P`, for the size of the stream
+    EstimateSend {
+        from: String,
+        to: String,
+    },
+    /// an incremental stream from `from` to `to` on stdout
+    Send {
+        from: String,
+        to: String,
+    },
+    /// a stream on stdin received into `filesystem`, replacing anything
+    /// written to it since the stream's base
+    Receive {
+        filesystem: String,
+    },
+    Diff {
+        snapshot: String,
+    },
+    Mount {
+        filesystem: String,
+        mounted: bool,
+    },
+    /// `fuser -m`, which exits with 0 if something has a file open under
+    /// `mountpoint`
+    MountpointInUse {
+        mountpoint: String,
+    },
+    /// the permissions `zfs allow` has delegated on `target`
+    ListPermissions {
+        target: String,
+    },
+    ZfsVersion,
+    FuserVersion,
+    UserId,
+    UserGroups,
+    /// a switch hook, run through `sh -c`
+    Hook {
+        command: String,
+    },
+}
+
+impl Operation {
+    /// The program and arguments that carry out the operation
+    pub fn command(&self) -> (&'static str, Vec<String>) {
+        let args = |args: &[&str]| args.iter().map(|arg| (*arg).to_owned()).collect();
+        match self {
+            Operation::ListSnapshots { filesystem } => {
+                let mut list: Vec<String> = args(&[
+                    "list",
+                    "-H",
+                    "-p",
+                    "-t",
+                    "snapshot",
+                    "-o",
+                    SNAPSHOT_PROPERTIES,
+                ]);
+                if let Some(filesystem) = filesystem {
+                    list.extend(args(&["-d", "1", "-S", "creation", filesystem]));
+                }
+                ("zfs", list)
+            }
+            Operation::GetProperty { target, property } => (
+                "zfs",
+                args(&["get", "-H", "-p", "-o", "value", property, target]),
+            ),
+            Operation::SetProperty {
+                filesystem,
+                property,
+                value,
+            } => (
+                "zfs",
+                args(&["set", &format!("{}={}", property, value), filesystem]),
+            ),
+            Operation::PoolProperty { pool, property } => {
+                ("zpool", args(&["list", "-H", "-p", "-o", property, pool]))
+            }
+            Operation::Snapshot { snapshot } => ("zfs", args(&["snapshot", snapshot])),
+            Operation::DestroySnapshot { snapshot } => ("zfs", args(&["destroy", snapshot])),
+            Operation::EstimateSend { from, to } => {
+                ("zfs", args(&["send", "-n", "-P", "-i", from, to]))
+            }
+            Operation::Send { from, to } => ("zfs", args(&["send", "-i", from, to])),
+            Operation::Receive { filesystem } => ("zfs", args(&["recv", "-F", filesystem])),
+            Operation::Diff { snapshot } => ("zfs", args(&["diff", "-H", snapshot])),
+            Operation::Mount {
+                filesystem,
+                mounted,
+            } => (
+                "zfs",
+                args(&[if *mounted { "mount" } else { "unmount" }, filesystem]),
+            ),
+            Operation::MountpointInUse { mountpoint } => ("fuser", args(&["-m", mountpoint])),
+            Operation::ListPermissions { target } => ("zfs", args(&["allow", target])),
+            Operation::ZfsVersion => ("zfs", args(&["version"])),
+            Operation::FuserVersion => ("fuser", args(&["-V"])),
+            Operation::UserId => ("id", args(&["-u"])),
+            Operation::UserGroups => ("id", args(&["-Gn"])),
+            Operation::Hook { command } => ("sh", args(&["-c", command])),
+        }
+    }
+
+    /// Whether the operation needs the server's privilege, which is
+    /// everything but hooks and `id`
+    pub fn privileged(&self) -> bool {
+        !matches!(
+            self,
+            Operation::UserId | Operation::UserGroups | Operation::Hook { .. }
+        )
+    }
+
+    /// Which pipe of the command is streamed
+    pub fn stream(&self) -> Stream {
+        match self {
+            Operation::Send { .. } => Stream::Stdout,
+            Operation::Receive { .. } => Stream::Stdin,
+            _ => Stream::None,
+        }
+    }
+
+    /// The command line, for errors and logs
+    pub fn line(&self) -> String {
+        let (program, args) = self.command();
+        std::iter::once(program.to_owned())
+            .chain(args)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// An `Operation` as JSON, with its HMAC-SHA256 over the challenge nonce and
+/// the JSON
+#[derive(Serialize, Deserialize)]
+pub struct SignedRequest {
+    pub body: String,
+    pub mac: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum AgentResponse {
+    Exited {
+        status: Option<i32>,
+        stdout: Vec<u8>,
+        stderr: Vec<u8>,
+    },
+    /// the command is running and its stream follows
+    Started,
+    Refused(ErrorCode),
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => u8::from_str_radix(std::str::from_utf8(&[*high, *low]).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+pub fn new_nonce() -> io::Result<String> {
+    let mut nonce = [0u8; 32];
+    ring::rand::SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| io::Error::other("no randomness for a nonce"))?;
+    Ok(hex(&nonce))
+}
+
+fn message(nonce: &str, body: &str) -> Vec<u8> {
+    [nonce.as_bytes(), b"\n", body.as_bytes()].concat()
+}
+
+pub fn sign(token: &[u8], nonce: &str, request: &Operation) -> io::Result<SignedRequest> {
+    let body = serde_json::to_string(request)?;
+    let key = hmac::Key::new(hmac::HMAC_SHA256, token);
+    let mac = hex(hmac::sign(&key, &message(nonce, &body)).as_ref());
+    Ok(SignedRequest { body, mac })
+}
+
+/// The request if it was signed with `token` for `nonce`
+pub fn verify(token: &[u8], nonce: &str, signed: &SignedRequest) -> Option<Operation> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, token);
+    let mac = unhex(&signed.mac)?;
+    hmac::verify(&key, &message(nonce, &signed.body), &mac).ok()?;
+    serde_json::from_str(&signed.body).ok()
+}
+
+/// Reads a token file, ignoring surrounding whitespace
+pub fn parse_token(contents: &str) -> io::Result<Vec<u8>> {
+    let token = contents.trim();
+    if token.len() < 16 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the agent token must be at least 16 characters",
+        ));
+    }
+    Ok(token.as_bytes().to_vec())
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, data: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(data.len()).map_err(|_| io::Error::other("frame too large"))?;
+    writer.write_all(&len.to_be_bytes()).await?;
+    writer.write_all(data).await?;
+    writer.flush().await
+}
+
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    read_frame_up_to(reader, MAX_FRAME).await
+}
+
+async fn read_frame_up_to<R: AsyncRead + Unpin>(reader: &mut R, max: usize) -> io::Result<Vec<u8>> {
+    let len = reader.read_u32().await? as usize;
+    if len > max {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is too large", len),
+        ));
+    }
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+pub async fn write_message<W: AsyncWrite + Unpin, T: Serialize>(
+    writer: &mut W,
+    message: &T,
+) -> io::Result<()> {
+    write_frame(writer, &serde_json::to_vec(message)?).await
+}
+
+pub async fn read_message<R: AsyncRead + Unpin, T: DeserializeOwned>(
+    reader: &mut R,
+) -> io::Result<T> {
+    Ok(serde_json::from_slice(&read_frame(reader).await?)?)
+}
+
+/// Reads the `SignedRequest` that follows a `Challenge`
+pub async fn read_request<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<SignedRequest> {
+    Ok(serde_json::from_slice(
+        &read_frame_up_to(reader, MAX_REQUEST_FRAME).await?,
+    )?)
+}
@@ -23,13 +23,54 @@ pub enum ErrorCode {
     ServerNotFoundFromRequest {
         server_name: String,
     },
+    /// A command that couldn't be run, or exited with an error
     ZfsCommandError {
+        server: String,
+        /// the full command line, including the privilege wrapper
+        command: String,
+        /// `None` if the command didn't run or was killed by a signal
+        exit_code: Option<i32>,
+        stderr: String,
+        msg: String,
+    },
+    ZfsOutputParseError {
+        command: String,
+        line: String,
         msg: String,
     },
     ConfigIsInvalidJson,
     ErrorWritingConfigFile {
         path: PathBuf,
     },
+    ErrorReadingConfigBackups {
+        path: PathBuf,
+    },
+    ConfigVersionNotFound {
+        version: String,
+    },
+    InvalidConfigVersion {
+        version: String,
+        msg: String,
+    },
+    /// restoring the version would move the dataset to another owner, which
+    /// only a switch may do
+    RestoreChangesOwner {
+        version: String,
+        dataset: String,
+        current: String,
+        restored: String,
+    },
+    /// A switch must move ownership to another server
+    AlreadyOwner {
+        dataset: String,
+        server: String,
+    },
+    /// The server a switch would move ownership to has no copy of the
+    /// dataset to take over
+    DatasetNotOnServer {
+        dataset: String,
+        server: String,
+    },
     DatasetNotSynced {
         dataset: String,
     },
@@ -53,17 +94,147 @@ pub enum ErrorCode {
         pool: String,
         dataset: String,
     },
+    InvalidSnapshotLabel {
+        label: String,
+    },
+    SnapshotNotPinned {
+        dataset: String,
+        snapshot: String,
+    },
+    /// The server lost ownership in a failover and may have snapshots the new
+    /// owner doesn't, which a sync onto it would destroy
+    FailoverNotAcknowledged {
+        dataset: String,
+        server: String,
+    },
+    /// Only a failover whose old owner was fenced, with its unreplicated
+    /// snapshots recorded, can be acknowledged
+    FailoverNotFenced {
+        dataset: String,
+        server: String,
+    },
+    FailoverNotFound {
+        dataset: String,
+        server: String,
+    },
+    NoFailoverCandidate {
+        dataset: String,
+    },
+    HookFailed {
+        server: String,
+        command: String,
+        exit_code: Option<i32>,
+        output: String,
+    },
+    HookCommandError {
+        server: String,
+        command: String,
+    },
+    HookTimedOut {
+        server: String,
+        command: String,
+        seconds: u64,
+    },
+    DatasetBusy {
+        dataset: String,
+        operation: String,
+    },
+    MountpointInUse {
+        server: String,
+        dataset: String,
+        mountpoint: String,
+    },
+    MountFail {
+        server: String,
+        dataset: String,
+        msg: String,
+    },
+    InvalidSchedule {
+        msg: String,
+    },
+    ScheduledSwitchNotFound {
+        id: u64,
+    },
+    ScheduledSwitchNotPending {
+        id: u64,
+    },
+    SwitchDeadlinePassed {
+        dataset: String,
+    },
+    /// The dataset is writable on a server other than its owner. Replicas
+    /// must be read-only, `POST /ownership/adopt` makes them so if they
+    /// haven't been written to.
+    SplitBrain {
+        dataset: String,
+        owner: String,
+        writable_on: Vec<String>,
+    },
+    /// A snapshot that was sent, but isn't on the destination as it is on the
+    /// source
+    ReplicaVerificationFailed {
+        dataset: String,
+        server: String,
+        snapshot: String,
+        reason: String,
+    },
+    AgentConnectionFail {
+        server: String,
+        msg: String,
+    },
+    /// The agent only runs the zfs, zpool and fuser commands brig needs, and
+    /// hooks if it was started with `--allow-hooks`
+    AgentCommandNotAllowed {
+        command: String,
+    },
+    ConnectTimeout {
+        server: String,
+        seconds: u64,
+    },
+    TransferStalled {
+        from: String,
+        to: String,
+        seconds: u64,
+    },
+    HostKeyMismatch {
+        server: String,
+        expected: String,
+        found: Vec<String>,
+    },
+    /// A pinned key is only trusted from a file no one else could have
+    /// written to
+    UnsafeKnownHosts {
+        server: String,
+        path: String,
+        reason: String,
+    },
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Dataset {
     pub pool: String,
+    /// empty for snapshots of the pool's root
     pub dataset: String,
     pub snapshot: String,
+    #[serde(default)]
+    pub guid: u64,
+    /// unix timestamp
+    #[serde(default)]
+    pub creation: i64,
+    /// bytes only this snapshot holds
+    #[serde(default)]
+    pub used: u64,
+    #[serde(default)]
+    pub referenced: u64,
+    /// bytes written since the previous snapshot
+    #[serde(default)]
+    pub written: u64,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Datasets {
     pub server: String,
     pub datasets: Vec<Dataset>,
+    /// datasets that are writable on this server
+    #[serde(default)]
+    pub writable: Vec<String>,
 }
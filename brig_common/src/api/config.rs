@@ -0,0 +1,14 @@
+use serde::{Deserialize, Serialize};
+
+/// A previous config that brig kept when it saved a new one
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ConfigVersion {
+    /// when it was replaced, as `YYYYmmddHHMMSS` followed by microseconds (UTC)
+    pub version: String,
+    pub size: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RestoreConfigRequest {
+    pub version: String,
+}
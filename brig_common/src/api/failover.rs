@@ -0,0 +1,10 @@
+use serde::{Deserialize, Serialize};
+
+/// Accepts that the snapshots a failover's old owner never replicated are
+/// lost, so the old owner is synced to again
+#[derive(Serialize, Deserialize)]
+pub struct AcknowledgeFailoverRequest {
+    pub dataset: String,
+    /// the old owner
+    pub server: String,
+}
@@ -1,3 +1,10 @@
+#[allow(clippy::module_inception)]
 pub mod api;
+pub mod config;
+pub mod failover;
+pub mod ownership;
+pub mod privilege;
+pub mod server;
+pub mod snapshot;
 pub mod switch;
 pub mod sync;
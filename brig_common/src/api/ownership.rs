@@ -0,0 +1,29 @@
+use serde::{Deserialize, Serialize};
+
+/// The `readonly` property of a dataset on one server
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReadonlyState {
+    pub server: String,
+    /// `None` if the server couldn't be reached or doesn't have the dataset
+    pub readonly: Option<bool>,
+}
+
+/// Which servers a dataset is actually writable on, compared to its owner in
+/// the config
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DatasetOwnership {
+    pub dataset: String,
+    pub owner: String,
+    pub servers: Vec<ReadonlyState>,
+    pub writable_on: Vec<String>,
+    /// writable on a server other than the owner, possibly as well as on the
+    /// owner
+    pub split_brain: bool,
+}
+
+/// Sets a dataset read-only on the replicas that are writable but were never
+/// written to, e.g. after upgrading from a brig that left replicas writable
+#[derive(Serialize, Deserialize)]
+pub struct AdoptRequest {
+    pub dataset: String,
+}
@@ -0,0 +1,17 @@
+use serde::{Deserialize, Serialize};
+
+use super::api::ErrorCode;
+
+/// Whether brig has the rights it needs to run zfs on one server
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PrivilegeCheck {
+    pub server: String,
+    /// `none`, `sudo`, `doas` or `wrapper`
+    pub privilege: String,
+    /// programs the privilege doesn't let brig run, e.g. `zpool`, or
+    /// `zfs allow` permissions missing on a dataset, e.g. `tank/data: receive`
+    pub missing: Vec<String>,
+    /// why the check itself couldn't run
+    pub error: Option<ErrorCode>,
+    pub ok: bool,
+}
@@ -0,0 +1,25 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::api::ErrorCode;
+
+/// What the last background probe of one server found. Fields that couldn't
+/// be read are `None`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ServerHealth {
+    pub server: String,
+    pub reachable: bool,
+    /// milliseconds for a trivial command to run on the server and return
+    pub latency_ms: Option<u64>,
+    /// the first line of `zfs version`, e.g. `zfs-2.1.11-1`
+    pub zfs_version: Option<String>,
+    pub pool_imported: bool,
+    /// as reported by `zpool list`, e.g. `ONLINE` or `DEGRADED`
+    pub pool_health: Option<String>,
+    /// why the server couldn't be reached or its pool read
+    pub error: Option<ErrorCode>,
+    pub checked_at: DateTime<Utc>,
+    /// reachable with its pool `ONLINE`, scheduled switches and failover skip
+    /// servers that aren't
+    pub healthy: bool,
+}
@@ -0,0 +1,33 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+pub struct SnapshotRequest {
+    pub dataset: String,
+    pub label: String,
+    /// keep the snapshot until it is unpinned, regardless of its lifetime
+    #[serde(default)]
+    pub pinned: bool,
+    /// send the snapshot to every other server right away
+    #[serde(default)]
+    pub replicate: bool,
+    /// seconds to wait for the dataset if it is busy with another operation
+    #[serde(default)]
+    pub wait: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SnapshotResponse {
+    pub snapshot: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UnpinRequest {
+    pub dataset: String,
+    pub snapshot: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PinnedSnapshots {
+    pub dataset: String,
+    pub snapshots: Vec<String>,
+}
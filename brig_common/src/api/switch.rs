@@ -1,7 +1,125 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+use super::api::ErrorCode;
+
 #[derive(Serialize, Deserialize)]
+pub struct ReadinessRequest {
+    pub dataset: String,
+}
+
+/// Counts of the change types `zfs diff` reports
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct DiffSummary {
+    pub added: usize,
+    pub removed: usize,
+    pub modified: usize,
+    pub renamed: usize,
+}
+
+impl DiffSummary {
+    pub fn is_empty(&self) -> bool {
+        self.added + self.removed + self.modified + self.renamed == 0
+    }
+}
+
+/// What one server has of the dataset. Fields that couldn't be read are `None`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ServerReadiness {
+    pub server: String,
+    pub reachable: bool,
+    pub latest_snapshot: Option<String>,
+    /// how many of the owner's snapshots are newer than `latest_snapshot`
+    pub snapshots_behind: Option<usize>,
+    /// seconds between the owner's latest snapshot and `latest_snapshot`
+    pub lag_seconds: Option<i64>,
+    pub readonly: Option<bool>,
+    /// why something couldn't be read from the server
+    #[serde(default)]
+    pub error: Option<ErrorCode>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Verdict {
+    Ready,
+    Unreachable {
+        servers: Vec<String>,
+    },
+    ReplicasBehind {
+        servers: Vec<String>,
+    },
+    UnsnapshottedChanges {
+        changes: DiffSummary,
+    },
+    /// `zfs diff` failed on the owner, see its `error`
+    ChangesUnknown,
+}
+
+/// Whether a dataset can be switched to any of its replicas right now, found
+/// without changing anything
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReadinessReport {
+    pub dataset: String,
+    pub owner: String,
+    pub servers: Vec<ServerReadiness>,
+    /// changes on the owner since its latest snapshot
+    pub changes: Option<DiffSummary>,
+    pub verdict: Verdict,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct SwitchRequest {
     pub dataset: String,
     pub new_server: String,
+    /// make the old owner read-only, send a final snapshot to the new owner
+    /// and only then switch, instead of requiring everything to be in sync
+    #[serde(default)]
+    pub planned: bool,
+    /// with `planned`, also send the final snapshot to every other replica
+    #[serde(default)]
+    pub replicate_all: bool,
+    /// seconds to wait for the dataset if it is busy with another operation
+    #[serde(default)]
+    pub wait: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum StepOutcome {
+    Done,
+    Failed,
+    RolledBack,
+    RollbackFailed,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SwitchStep {
+    pub name: String,
+    pub outcome: StepOutcome,
+    /// what a hook printed
+    #[serde(default)]
+    pub output: Option<String>,
+}
+
+/// What a switch did, and what it undid if it failed part way through
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SwitchReport {
+    pub dataset: String,
+    pub old_server: String,
+    pub new_server: String,
+    pub steps: Vec<SwitchStep>,
+    pub error: Option<ErrorCode>,
+}
+
+/// A switch to run at `at`, unless it can't start before `deadline`
+#[derive(Serialize, Deserialize)]
+pub struct ScheduleSwitchRequest {
+    #[serde(flatten)]
+    pub switch: SwitchRequest,
+    pub at: DateTime<Utc>,
+    pub deadline: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CancelSwitchRequest {
+    pub id: u64,
 }
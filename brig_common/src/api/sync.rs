@@ -3,4 +3,7 @@ use serde::{Deserialize, Serialize};
 #[derive(Serialize, Deserialize)]
 pub struct SyncRequest {
     pub datasets: Vec<String>,
+    /// seconds to wait for a dataset that is busy with another operation
+    #[serde(default)]
+    pub wait: Option<u64>,
 }
@@ -1,84 +1,241 @@
-use chrono::{Duration, Local};
-use openssh::{KnownHosts, Session};
-use regex::Regex;
+use std::collections::HashSet;
 
-use crate::ConfigRef;
+use brig_common::api::api::ErrorCode;
+use chrono::Local;
 
-pub async fn clean(config: ConfigRef) -> warp::reply::Json {
-    let config = &config.read().await;
-    for dataset in &config.datasets {
-        let snapshot_expiration_duration = match &dataset.snapshot_lifetime.chars().last().unwrap()
+use crate::{
+    ConfigRef, ConnectorRef, Leases,
+    config::{config::Config, dataset::Dataset, lifetime::Lifetime, server::Server},
+    exec::ExecutorRef,
+    lease::Lease,
+    utils,
+    zfs::{self, Snapshot},
+};
+
+/// Names of the latest snapshots the owner has in common with each replica.
+/// These are the bases for the next incremental send and must survive cleanup
+/// regardless of their age.
+async fn replication_bases(
+    dataset: &Dataset,
+    snapshots: &[(&Server, ExecutorRef, Vec<Snapshot>)],
+) -> Result<HashSet<String>, ErrorCode> {
+    let (_, _, owner_snapshots) = snapshots
+        .iter()
+        .find(|(server, _, _)| server.name == dataset.server)
+        .ok_or(ErrorCode::ServerNotFoundFromDataset {
+            dataset: dataset.name.clone(),
+            server_name: dataset.server.clone(),
+        })?;
+
+    let mut bases = HashSet::new();
+    for (server, _, replica_snapshots) in snapshots {
+        if server.name == dataset.server {
+            continue;
+        }
+        if let Ok(common) =
+            utils::find_latest_common_snapshot(&dataset.name, owner_snapshots, replica_snapshots)
+                .await
         {
-            'M' => Some(Duration::days(
-                30 as i64
-                    * (&dataset
-                        .snapshot_lifetime
-                        .trim_end_matches('M')
-                        .parse()
-                        .unwrap()),
-            )),
-            'w' => Some(Duration::days(
-                7 as i64
-                    * (&dataset
-                        .snapshot_lifetime
-                        .trim_end_matches('w')
-                        .parse()
-                        .unwrap()),
-            )),
-            'd' => Some(Duration::days(
-                dataset
-                    .snapshot_lifetime
-                    .trim_end_matches('d')
-                    .parse()
-                    .unwrap(),
-            )),
-            _ => None,
-        };
+            bases.insert(common.short_name().to_owned());
+        }
+    }
+    Ok(bases)
+}
+
+/// Replication bases and pinned snapshots, neither of which may be destroyed
+async fn protected_snapshots(
+    dataset: &Dataset,
+    snapshots: &[(&Server, ExecutorRef, Vec<Snapshot>)],
+) -> Result<HashSet<String>, ErrorCode> {
+    let mut protected = replication_bases(dataset, snapshots).await?;
+    protected.extend(dataset.pinned_snapshots.iter().cloned());
+    Ok(protected)
+}
 
-        let snapshot_expiration = Local::now() - snapshot_expiration_duration.unwrap();
-        let snapshot_expiration = snapshot_expiration.format("%Y%m%d%H%M%S").to_string();
+/// Snapshots of `dataset` on each of `servers`
+async fn list_dataset_snapshots<'a>(
+    servers: &[&'a Server],
+    connector: &ConnectorRef,
+    dataset: &Dataset,
+) -> Result<Vec<(&'a Server, ExecutorRef, Vec<Snapshot>)>, ErrorCode> {
+    let mut snapshots = vec![];
+    for &server in servers {
+        let session = connector.connect(server).await?;
+        let server_snapshots = zfs::snapshots(&session, &server.pool, &dataset.name).await?;
+        snapshots.push((server, session, server_snapshots));
+    }
+    Ok(snapshots)
+}
 
-        for server in &config.servers {
-            let session = Session::connect(
-                format!("{}@{}", &server.user, &server.address),
-                KnownHosts::Strict,
-            )
-            .await
-            .unwrap();
+async fn clean_dataset(
+    config: &Config,
+    connector: &ConnectorRef,
+    dataset: &Dataset,
+) -> Result<(), ErrorCode> {
+    let servers: Vec<&Server> = config.servers.iter().collect();
+    let snapshots = list_dataset_snapshots(&servers, connector, dataset).await?;
+    let protected = protected_snapshots(dataset, &snapshots).await?;
 
-            let output = session
-                .command("zfs")
-                .arg("list")
-                .arg("-t")
-                .arg("snapshot")
-                .arg("-o")
-                .arg("name")
-                .arg("-s")
-                .arg("creation")
-                .arg(format!("{}/{}", &server.pool, &dataset.name))
-                .output()
-                .await
-                .unwrap();
-            let stdout = String::from_utf8_lossy(&output.stdout);
+    for (server, session, server_snapshots) in &snapshots {
+        let Some(snapshot_expiration) = dataset.lifetime_on(server).cutoff(Local::now()) else {
+            continue;
+        };
+
+        for snapshot in server_snapshots {
+            if protected.contains(snapshot.short_name()) {
+                continue;
+            }
+            if config
+                .snapshot_naming
+                .parse(snapshot.short_name())
+                .is_some_and(|parsed| parsed.created < snapshot_expiration)
+            {
+                utils::destroy_snapshot(session, &snapshot.name).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Destroys the oldest snapshots taken by brig on `server`, across the datasets
+/// stored on it, until its pool is no fuller than `server.capacity_threshold`.
+/// Only `server` and the servers it shares replication bases with are
+/// connected to. Replication bases, pinned snapshots and snapshots whose
+/// lifetime on `server` is `never` are never pruned. Datasets busy with
+/// another operation are skipped, except `held`, whose lease the caller
+/// already has.
+pub async fn prune_for_space(
+    config: &Config,
+    connector: &ConnectorRef,
+    leases: &Leases,
+    server: &Server,
+    held: Option<&str>,
+) -> Result<(), ErrorCode> {
+    let Some(threshold) = server.capacity_threshold else {
+        return Ok(());
+    };
+    let threshold = u64::from(threshold);
+    let session = connector.connect(server).await?;
+    let capacity = utils::get_pool_capacity(&session, &server.pool).await?;
+    if capacity <= threshold {
+        return Ok(());
+    }
+    println!(
+        "pool {} on {} is at {}% capacity (threshold {}%), pruning snapshots",
+        &server.pool, &server.name, capacity, threshold
+    );
 
-            let brig_pattern = Regex::new(r"@brig-(\d{14})$").unwrap();
-            for line in stdout.lines() {
-                if line.starts_with(&format!("{}/{}@brig-", &server.pool, &dataset.name)) {
-                    if let Some(caps) = brig_pattern.captures(line) {
-                        let timestamp = &caps[1];
-                        if *timestamp < *snapshot_expiration {
-                            session
-                                .command("zfs")
-                                .arg("destroy")
-                                .arg(line)
-                                .status()
-                                .await
-                                .unwrap();
-                        }
-                    }
+    let mut candidates = vec![];
+    let mut dataset_leases: Vec<Lease> = vec![];
+    for dataset in &config.datasets {
+        // datasets that aren't stored on the server have nothing to prune
+        let Ok(server_snapshots) = zfs::snapshots(&session, &server.pool, &dataset.name).await
+        else {
+            continue;
+        };
+        if server_snapshots.is_empty() || dataset.lifetime_on(server) == Lifetime::Never {
+            continue;
+        }
+        if held != Some(dataset.name.as_str()) {
+            match leases.try_acquire(&dataset.name, "clean") {
+                Ok(lease) => dataset_leases.push(lease),
+                Err(e) => {
+                    println!("  skipping dataset {}: {:?}", &dataset.name, &e);
+                    continue;
                 }
             }
         }
+        // a replica only shares replication bases with the owner, while the
+        // owner shares one with every replica
+        let others: Vec<&Server> = config
+            .servers
+            .iter()
+            .filter(|other| {
+                other.name != server.name
+                    && (server.name == dataset.server || other.name == dataset.server)
+            })
+            .collect();
+        let mut snapshots = list_dataset_snapshots(&others, connector, dataset).await?;
+        snapshots.push((server, session.clone(), server_snapshots));
+        let protected = protected_snapshots(dataset, &snapshots).await?;
+        let Some((_, _, server_snapshots)) = snapshots.last() else {
+            continue;
+        };
+        for snapshot in server_snapshots {
+            if protected.contains(snapshot.short_name()) {
+                continue;
+            }
+            if let Some(parsed) = config.snapshot_naming.parse(snapshot.short_name()) {
+                candidates.push((parsed.created, snapshot.name.clone()));
+            }
+        }
+    }
+    // oldest first
+    candidates.sort();
+
+    // zfs only frees the space of a destroyed snapshot once its transaction
+    // group syncs, so the pool's own figures lag behind and the space each
+    // snapshot held is subtracted instead
+    let size = utils::get_pool_number(&session, &server.pool, "size").await?;
+    let mut allocated = utils::get_pool_number(&session, &server.pool, "allocated").await?;
+    let target = size * threshold / 100;
+    let mut reclaimed: u64 = 0;
+    for (_, snapshot) in candidates {
+        if allocated <= target {
+            break;
+        }
+        let used = utils::get_property(&session, &snapshot, "used")
+            .await?
+            .parse::<u64>()
+            .unwrap_or(0);
+        utils::destroy_snapshot(&session, &snapshot).await?;
+        reclaimed += used;
+        allocated = allocated.saturating_sub(used);
+        println!("  pruned {} ({} bytes)", &snapshot, used);
+    }
+
+    println!(
+        "reclaimed {} bytes on {}, pool {} should now be at about {}% capacity",
+        reclaimed,
+        &server.name,
+        &server.pool,
+        allocated * 100 / size.max(1)
+    );
+    if allocated > target {
+        println!(
+            "pool {} on {} is still above its threshold, no more snapshots can be pruned",
+            &server.pool, &server.name
+        );
+    }
+    Ok(())
+}
+
+pub async fn clean(
+    config: ConfigRef,
+    leases: Leases,
+    connector: ConnectorRef,
+) -> warp::reply::Json {
+    let config = { config.read().await.clone() };
+    let mut errors = vec![];
+    for dataset in &config.datasets {
+        let _lease = match leases.try_acquire(&dataset.name, "clean") {
+            Ok(lease) => lease,
+            Err(e) => {
+                println!("skipping dataset {}: {:?}", &dataset.name, &e);
+                errors.push(e);
+                continue;
+            }
+        };
+        if let Err(e) = clean_dataset(&config, &connector, dataset).await {
+            println!("failed to clean dataset {}: {:?}", &dataset.name, &e);
+            errors.push(e);
+        }
+    }
+    for server in &config.servers {
+        if let Err(e) = prune_for_space(&config, &connector, &leases, server, None).await {
+            println!("failed to prune snapshots on {}: {:?}", &server.name, &e);
+            errors.push(e);
+        }
     }
-    warp::reply::json(&())
+    warp::reply::json(&errors)
 }
@@ -0,0 +1,87 @@
+use std::{path::PathBuf, sync::Arc};
+
+use brig_common::api::{api::ErrorCode, config::RestoreConfigRequest};
+
+use crate::{
+    ConfigRef, ConnectorRef, Leases,
+    config::{backup, config::Config},
+    lease::Lease,
+};
+
+pub async fn versions(config_path: Arc<PathBuf>) -> warp::reply::Json {
+    match backup::list_versions(&config_path) {
+        Ok(versions) => warp::reply::json(&versions),
+        Err(e) => warp::reply::json(&e),
+    }
+}
+
+/// Makes a previous version the current config. The config it replaces is
+/// backed up like on any other save, so a restore can itself be undone.
+/// Every dataset in either version must be idle, and a restore may not give a
+/// dataset another owner, since that skips everything a switch does.
+pub async fn restore(
+    req: RestoreConfigRequest,
+    config_path: Arc<PathBuf>,
+    config_arc: ConfigRef,
+    leases: Leases,
+    connector: ConnectorRef,
+) -> warp::reply::Json {
+    let version_path = match backup::version_path(&config_path, &req.version) {
+        Ok(version_path) => version_path,
+        Err(e) => return warp::reply::json(&e),
+    };
+    let restored = match Config::load(&version_path) {
+        Ok(restored) => restored,
+        Err(e) => {
+            return warp::reply::json(&ErrorCode::InvalidConfigVersion {
+                version: req.version.clone(),
+                msg: format!("{:#}", e),
+            });
+        }
+    };
+
+    let names: Vec<String> = {
+        let config = config_arc.read().await;
+        config
+            .datasets
+            .iter()
+            .chain(&restored.datasets)
+            .map(|dataset| dataset.name.clone())
+            .collect()
+    };
+    let mut dataset_leases = vec![];
+    for name in names {
+        if dataset_leases
+            .iter()
+            .any(|lease: &Lease| lease.dataset() == name)
+        {
+            continue;
+        }
+        match leases.try_acquire(&name, "restore config") {
+            Ok(lease) => dataset_leases.push(lease),
+            Err(e) => return warp::reply::json(&e),
+        }
+    }
+
+    let mut config = config_arc.write().await;
+    for dataset in &restored.datasets {
+        let Some(current) = config.datasets.iter().find(|ds| ds.name == dataset.name) else {
+            continue;
+        };
+        if current.server != dataset.server {
+            return warp::reply::json(&ErrorCode::RestoreChangesOwner {
+                version: req.version.clone(),
+                dataset: dataset.name.clone(),
+                current: current.server.clone(),
+                restored: dataset.server.clone(),
+            });
+        }
+    }
+    if let Err(e) = restored.save(&config_path) {
+        return warp::reply::json(&e);
+    }
+    connector.configure(&restored);
+    *config = restored;
+    println!("restored config version {}", &req.version);
+    warp::reply::json(&())
+}
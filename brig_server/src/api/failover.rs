@@ -0,0 +1,62 @@
+use std::{path::PathBuf, sync::Arc};
+
+use brig_common::api::{api::ErrorCode, failover::AcknowledgeFailoverRequest};
+
+use crate::{ConfigRef, Failovers, config::dataset::Dataset};
+
+pub async fn failovers(failovers: Failovers) -> warp::reply::Json {
+    warp::reply::json(&failovers.list().await)
+}
+
+pub async fn acknowledge(
+    req: AcknowledgeFailoverRequest,
+    config_path: Arc<PathBuf>,
+    config: ConfigRef,
+    failovers: Failovers,
+) -> warp::reply::Json {
+    let mut config = config.write().await;
+    let Some(dataset) = config
+        .datasets
+        .iter_mut()
+        .find(|ds: &&mut Dataset| ds.name == req.dataset)
+    else {
+        return warp::reply::json(&ErrorCode::DatasetNotFoundInConfig {
+            dataset: req.dataset.clone(),
+        });
+    };
+    let Some(pos) = dataset
+        .fenced_servers
+        .iter()
+        .position(|server| *server == req.server)
+    else {
+        return warp::reply::json(&ErrorCode::FailoverNotFound {
+            dataset: req.dataset.clone(),
+            server: req.server.clone(),
+        });
+    };
+    // until the old owner is fenced, what it would lose isn't known
+    if failovers.list().await.iter().any(|failover| {
+        failover.dataset == req.dataset && failover.old_server == req.server && !failover.fenced
+    }) {
+        return warp::reply::json(&ErrorCode::FailoverNotFenced {
+            dataset: req.dataset.clone(),
+            server: req.server.clone(),
+        });
+    }
+    dataset.fenced_servers.remove(pos);
+
+    if let Err(e) = config.save(&config_path) {
+        if let Some(dataset) = config
+            .datasets
+            .iter_mut()
+            .find(|ds: &&mut Dataset| ds.name == req.dataset)
+        {
+            dataset.fenced_servers.insert(pos, req.server.clone());
+        }
+        return warp::reply::json(&e);
+    }
+    if let Err(e) = failovers.acknowledge(&req.dataset, &req.server).await {
+        return warp::reply::json(&e);
+    }
+    warp::reply::json(&())
+}
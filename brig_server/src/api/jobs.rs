@@ -0,0 +1,9 @@
+use crate::Jobs;
+
+pub async fn jobs(jobs: Jobs) -> warp::reply::Json {
+    let mut jobs_to_return = vec![];
+    for job in jobs.read().await.iter() {
+        jobs_to_return.push(job.read().await.clone());
+    }
+    warp::reply::json(&jobs_to_return)
+}
@@ -2,6 +2,15 @@ pub use self::clean::clean;
 pub use self::status::status;
 pub use self::switch::switch;
 pub mod clean;
+pub mod config_versions;
+pub mod failover;
+pub mod jobs;
+pub mod ownership;
+pub mod privilege;
+pub mod readiness;
+pub mod schedule;
+pub mod server;
+pub mod snapshot;
 pub mod status;
 pub mod switch;
 pub mod sync;
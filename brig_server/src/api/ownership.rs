@@ -0,0 +1,164 @@
+use brig_common::api::{
+    api::ErrorCode,
+    ownership::{AdoptRequest, DatasetOwnership, ReadonlyState},
+};
+
+use crate::{
+    ConfigRef, ConnectorRef, Leases,
+    config::{config::Config, dataset::Dataset, server::Server},
+    utils,
+};
+
+/// Reads `readonly` for `dataset` on every server and flags it if it's
+/// writable anywhere but on its owner
+pub async fn check_dataset(
+    config: &Config,
+    connector: &ConnectorRef,
+    dataset: &Dataset,
+) -> DatasetOwnership {
+    let mut servers = vec![];
+    for server in &config.servers {
+        let readonly = match connector.connect(server).await {
+            Ok(session) => utils::get_readonly(&session, server, &dataset.name)
+                .await
+                .ok(),
+            Err(_) => None,
+        };
+        servers.push(ReadonlyState {
+            server: server.name.clone(),
+            readonly,
+        });
+    }
+
+    let writable_on: Vec<String> = servers
+        .iter()
+        .filter(|state| state.readonly == Some(false))
+        .map(|state| state.server.clone())
+        .collect();
+    let split_brain = writable_on.iter().any(|server| *server != dataset.server);
+    DatasetOwnership {
+        dataset: dataset.name.clone(),
+        owner: dataset.server.clone(),
+        servers,
+        writable_on,
+        split_brain,
+    }
+}
+
+pub async fn check_all(config: &Config, connector: &ConnectorRef) -> Vec<DatasetOwnership> {
+    let mut ownerships = vec![];
+    for dataset in &config.datasets {
+        let ownership = check_dataset(config, connector, dataset).await;
+        if ownership.split_brain {
+            println!(
+                "dataset {} is owned by {} but writable on {}",
+                &ownership.dataset,
+                &ownership.owner,
+                ownership.writable_on.join(", ")
+            );
+        }
+        ownerships.push(ownership);
+    }
+    ownerships
+}
+
+/// Fails with `ErrorCode::SplitBrain` if `dataset` is writable on any server
+/// other than its owner, as replicating from the owner would then discard
+/// writes made elsewhere. Replicas must be read-only, `POST /ownership/adopt`
+/// sets them so where that loses nothing.
+pub async fn ensure_single_writer(
+    config: &Config,
+    connector: &ConnectorRef,
+    dataset: &Dataset,
+) -> Result<(), ErrorCode> {
+    let ownership = check_dataset(config, connector, dataset).await;
+    if ownership.split_brain {
+        return Err(ErrorCode::SplitBrain {
+            dataset: ownership.dataset,
+            owner: ownership.owner,
+            writable_on: ownership.writable_on,
+        });
+    }
+    Ok(())
+}
+
+/// Sets `dataset` read-only on every writable replica that hasn't been
+/// written to since its latest snapshot, which is how brig left replicas
+/// before it required them to be read-only. Fails with
+/// `ErrorCode::SplitBrain` naming the replicas that were written to, which
+/// are left alone as a sync would discard their writes.
+async fn adopt_dataset(
+    config: &Config,
+    connector: &ConnectorRef,
+    dataset: &Dataset,
+) -> Result<DatasetOwnership, ErrorCode> {
+    // the owner must answer, so it's known to be where the writes go
+    let owner = config
+        .servers
+        .iter()
+        .find(|server: &&Server| server.name == dataset.server)
+        .ok_or(ErrorCode::ServerNotFoundFromDataset {
+            dataset: dataset.name.clone(),
+            server_name: dataset.server.clone(),
+        })?;
+    let session = connector.connect(owner).await?;
+    utils::get_readonly(&session, owner, &dataset.name).await?;
+
+    let ownership = check_dataset(config, connector, dataset).await;
+    let mut written_on = vec![];
+    for server in &config.servers {
+        if server.name == dataset.server || !ownership.writable_on.contains(&server.name) {
+            continue;
+        }
+        let session = connector.connect(server).await?;
+        let filesystem = format!("{}/{}", &server.pool, &dataset.name);
+        if utils::get_property(&session, &filesystem, "written").await? != "0" {
+            written_on.push(server.name.clone());
+            continue;
+        }
+        utils::set_readonly(&session, server, &dataset.name, true).await?;
+        println!(
+            "set {} read-only on replica {}",
+            &dataset.name, &server.name
+        );
+    }
+    if !written_on.is_empty() {
+        return Err(ErrorCode::SplitBrain {
+            dataset: dataset.name.clone(),
+            owner: dataset.server.clone(),
+            writable_on: written_on,
+        });
+    }
+    Ok(check_dataset(config, connector, dataset).await)
+}
+
+pub async fn adopt(
+    req: AdoptRequest,
+    config: ConfigRef,
+    leases: Leases,
+    connector: ConnectorRef,
+) -> warp::reply::Json {
+    let _lease = match leases.acquire(&req.dataset, "adopt", None).await {
+        Ok(lease) => lease,
+        Err(e) => return warp::reply::json(&e),
+    };
+    let config = { config.read().await.clone() };
+    let Some(dataset) = config
+        .datasets
+        .iter()
+        .find(|ds: &&Dataset| ds.name == req.dataset)
+    else {
+        return warp::reply::json(&ErrorCode::DatasetNotFoundInConfig {
+            dataset: req.dataset.clone(),
+        });
+    };
+    match adopt_dataset(&config, &connector, dataset).await {
+        Ok(ownership) => warp::reply::json(&ownership),
+        Err(e) => warp::reply::json(&e),
+    }
+}
+
+pub async fn ownership(config: ConfigRef, connector: ConnectorRef) -> warp::reply::Json {
+    let config = { config.read().await.clone() };
+    warp::reply::json(&check_all(&config, &connector).await)
+}
@@ -0,0 +1,244 @@
+use std::collections::HashSet;
+
+use brig_common::{agent::Operation, api::privilege::PrivilegeCheck};
+
+use crate::{
+    ConfigRef, ConnectorRef,
+    config::{
+        config::Config,
+        server::{Privilege, Server},
+    },
+    exec::ExecutorRef,
+};
+
+/// The `zfs allow` permissions brig uses on a dataset when it runs zfs
+/// without privilege
+const ZFS_PERMISSIONS: [&str; 10] = [
+    "create", "destroy", "mount", "snapshot", "send", "receive", "rollback", "diff", "readonly",
+    "canmount",
+];
+
+/// Permissions `zfs allow` lists for `user`, one of its `groups` or everyone
+/// that apply to `dataset`. Grants on `dataset` itself count unless they're
+/// only for descendents, grants on its ancestors only if they're inherited.
+/// `dataset` doesn't have to exist yet, so the permissions a received
+/// dataset will get can be read from its parent. Permission sets (`@name`)
+/// aren't expanded.
+fn granted_permissions(
+    output: &str,
+    dataset: &str,
+    user: &str,
+    groups: &[String],
+) -> HashSet<String> {
+    let mut granted = HashSet::new();
+    let mut on_dataset = false;
+    let mut applies = false;
+    for line in output.lines() {
+        if let Some(target) = line.strip_prefix("---- Permissions on ") {
+            on_dataset = target.trim_end_matches(['-', ' ']) == dataset;
+            applies = false;
+            continue;
+        }
+        match line.trim() {
+            "Local+Descendent permissions:" => applies = true,
+            "Local permissions:" => applies = on_dataset,
+            "Descendent permissions:" => applies = !on_dataset,
+            // create time permissions and permission sets aren't grants
+            header if header.ends_with(':') => applies = false,
+            _ if !applies => {}
+            entry => {
+                let mut fields = entry.split_whitespace();
+                let permissions = match fields.next() {
+                    Some("user") if fields.next() == Some(user) => fields.next(),
+                    Some("group")
+                        if fields
+                            .next()
+                            .is_some_and(|group| groups.iter().any(|g| g == group)) =>
+                    {
+                        fields.next()
+                    }
+                    Some("everyone") => fields.next(),
+                    _ => None,
+                };
+                granted.extend(permissions.unwrap_or_default().split(',').map(String::from));
+            }
+        }
+    }
+    granted.remove("");
+    granted
+}
+
+/// Runs a harmless command through the privilege for every program brig
+/// needs it for, returning those it couldn't run
+async fn missing_programs(session: &ExecutorRef, server: &Server) -> Vec<String> {
+    let probes = [
+        (
+            "zfs",
+            Operation::GetProperty {
+                target: server.pool.clone(),
+                property: "name".to_owned(),
+            },
+        ),
+        (
+            "zpool",
+            Operation::PoolProperty {
+                pool: server.pool.clone(),
+                property: "name".to_owned(),
+            },
+        ),
+        ("fuser", Operation::FuserVersion),
+    ];
+    let mut missing = vec![];
+    for (program, operation) in probes {
+        let ran = session.command(operation).short().output().await;
+        if !ran.is_ok_and(|output| output.success()) {
+            missing.push(program.to_owned());
+        }
+    }
+    missing
+}
+
+/// `zfs allow` permissions the login user lacks on each dataset, or on the
+/// pool for datasets that haven't been received yet
+async fn missing_permissions(
+    session: &ExecutorRef,
+    config: &Config,
+    server: &Server,
+) -> Vec<String> {
+    let id = |operation: Operation| async move {
+        session
+            .command(operation)
+            .short()
+            .output()
+            .await
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+            .unwrap_or_default()
+    };
+    if id(Operation::UserId).await == "0" {
+        return vec![];
+    }
+    let groups: Vec<String> = id(Operation::UserGroups)
+        .await
+        .split_whitespace()
+        .map(String::from)
+        .collect();
+
+    let mut missing = vec![];
+    for dataset in &config.datasets {
+        let path = format!("{}/{}", &server.pool, &dataset.name);
+        let mut target = path.clone();
+        let mut output = session
+            .command(Operation::ListPermissions {
+                target: target.clone(),
+            })
+            .short()
+            .output()
+            .await;
+        if !output.as_ref().is_ok_and(|output| output.success()) {
+            target = server.pool.clone();
+            output = session
+                .command(Operation::ListPermissions {
+                    target: target.clone(),
+                })
+                .short()
+                .output()
+                .await;
+        }
+        let allowed = match output {
+            Ok(output) if output.success() => String::from_utf8_lossy(&output.stdout).to_string(),
+            _ => {
+                missing.push(format!("{}: zfs allow failed", &target));
+                continue;
+            }
+        };
+        let granted = granted_permissions(&allowed, &path, &server.user, &groups);
+        for permission in ZFS_PERMISSIONS {
+            if !granted.contains(permission) {
+                missing.push(format!("{}: {}", &target, permission));
+            }
+        }
+    }
+    missing
+}
+
+pub async fn check_server(
+    config: &Config,
+    connector: &ConnectorRef,
+    server: &Server,
+) -> PrivilegeCheck {
+    let (missing, error) = match connector.connect(server).await {
+        Ok(session) => {
+            let missing = match server.privilege() {
+                Privilege::None => missing_permissions(&session, config, server).await,
+                _ => missing_programs(&session, server).await,
+            };
+            (missing, None)
+        }
+        Err(err) => (vec![], Some(err)),
+    };
+    PrivilegeCheck {
+        server: server.name.clone(),
+        privilege: server.privilege().name().to_owned(),
+        ok: missing.is_empty() && error.is_none(),
+        missing,
+        error,
+    }
+}
+
+pub async fn privileges(config: ConfigRef, connector: ConnectorRef) -> warp::reply::Json {
+    let config = { config.read().await.clone() };
+    let mut checks = vec![];
+    for server in &config.servers {
+        checks.push(check_server(&config, &connector, server).await);
+    }
+    warp::reply::json(&checks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALLOWED: &str = "\
+---- Permissions on tank/data ----------------------------------------
+Permission sets:
+\t@backup send,snapshot
+Create time permissions:
+\tdestroy
+Local permissions:
+\tuser brig snapshot
+Descendent permissions:
+\tuser brig rollback
+---- Permissions on tank ---------------------------------------------
+Local+Descendent permissions:
+\tgroup admins destroy
+\teveryone mount
+Local permissions:
+\tuser brig create
+Descendent permissions:
+\tuser brig receive
+";
+
+    fn granted(output: &str, dataset: &str) -> Vec<String> {
+        let mut granted: Vec<_> =
+            granted_permissions(output, dataset, "brig", &["admins".to_owned()])
+                .into_iter()
+                .collect();
+        granted.sort();
+        granted
+    }
+
+    #[test]
+    fn only_counts_grants_that_reach_the_dataset() {
+        assert_eq!(
+            granted(ALLOWED, "tank/data"),
+            ["destroy", "mount", "receive", "snapshot"]
+        );
+    }
+
+    #[test]
+    fn reads_inherited_grants_for_datasets_not_received_yet() {
+        // what `zfs allow tank` prints
+        let pool = &ALLOWED[ALLOWED.find("---- Permissions on tank -").unwrap()..];
+        assert_eq!(granted(pool, "tank/other"), ["destroy", "mount", "receive"]);
+    }
+}
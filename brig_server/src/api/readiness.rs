@@ -0,0 +1,158 @@
+use brig_common::api::{
+    api::ErrorCode,
+    switch::{ReadinessReport, ReadinessRequest, ServerReadiness, Verdict},
+};
+
+use crate::{
+    ConfigRef, ConnectorRef,
+    config::{config::Config, dataset::Dataset, server::Server},
+    exec::ExecutorRef,
+    utils,
+    zfs::{self, Snapshot},
+};
+
+/// What could be read of `dataset` on one server
+struct Probe<'a> {
+    server: &'a Server,
+    session: Option<ExecutorRef>,
+    snapshots: Option<Vec<Snapshot>>,
+    readonly: Option<bool>,
+    error: Option<ErrorCode>,
+}
+
+async fn probe<'a>(server: &'a Server, connector: &ConnectorRef, dataset: &Dataset) -> Probe<'a> {
+    let session = match connector.connect(server).await {
+        Ok(session) => session,
+        Err(e) => {
+            return Probe {
+                server,
+                session: None,
+                snapshots: None,
+                readonly: None,
+                error: Some(e),
+            };
+        }
+    };
+    let snapshots = zfs::snapshots(&session, &server.pool, &dataset.name).await;
+    let readonly = utils::get_readonly(&session, server, &dataset.name).await;
+    let error = snapshots
+        .as_ref()
+        .err()
+        .or(readonly.as_ref().err())
+        .cloned();
+    Probe {
+        server,
+        session: Some(session),
+        snapshots: snapshots.ok(),
+        readonly: readonly.ok(),
+        error,
+    }
+}
+
+/// Compares every server's snapshots of `dataset` with the owner's and checks
+/// the owner for changes since its latest snapshot. Only reads state, a switch
+/// is ready when every server has the owner's latest snapshot and nothing has
+/// been written since.
+pub async fn check(
+    config: &Config,
+    connector: &ConnectorRef,
+    dataset: &Dataset,
+) -> Result<ReadinessReport, ErrorCode> {
+    let mut probes = vec![];
+    for server in &config.servers {
+        probes.push(probe(server, connector, dataset).await);
+    }
+    let owner = probes
+        .iter()
+        .find(|probe| probe.server.name == dataset.server)
+        .ok_or(ErrorCode::ServerNotFoundFromDataset {
+            dataset: dataset.name.clone(),
+            server_name: dataset.server.clone(),
+        })?;
+    let owner_snapshots = owner.snapshots.as_deref().unwrap_or_default();
+    let owner_latest = owner_snapshots.first();
+
+    // a failed diff is reported on the owner rather than failing the report
+    let changes = match (&owner.session, owner_latest) {
+        (Some(session), Some(latest)) => Some(utils::zfs_diff(session, &latest.name).await),
+        _ => None,
+    };
+    let diff_error = changes.as_ref().and_then(|changes| changes.as_ref().err());
+
+    let mut servers = vec![];
+    for probe in &probes {
+        let latest = probe.snapshots.as_ref().and_then(|s| s.first());
+        let snapshots_behind =
+            latest.and_then(|latest| owner_snapshots.iter().position(|s| s.guid == latest.guid));
+        let lag_seconds = latest
+            .zip(owner_latest)
+            .map(|(latest, owner_latest)| owner_latest.creation - latest.creation);
+        servers.push(ServerReadiness {
+            server: probe.server.name.clone(),
+            reachable: probe.session.is_some(),
+            latest_snapshot: latest.map(|latest| latest.name.clone()),
+            snapshots_behind,
+            lag_seconds,
+            readonly: probe.readonly,
+            error: match diff_error {
+                Some(e) if probe.server.name == dataset.server => Some(e.clone()),
+                _ => probe.error.clone(),
+            },
+        });
+    }
+
+    let unreachable: Vec<String> = servers
+        .iter()
+        .filter(|server| !server.reachable)
+        .map(|server| server.server.clone())
+        .collect();
+    let behind: Vec<String> = servers
+        .iter()
+        .filter(|server| server.snapshots_behind != Some(0))
+        .map(|server| server.server.clone())
+        .collect();
+    let verdict = if !unreachable.is_empty() {
+        Verdict::Unreachable {
+            servers: unreachable,
+        }
+    } else if !behind.is_empty() {
+        Verdict::ReplicasBehind { servers: behind }
+    } else {
+        match &changes {
+            Some(Err(_)) => Verdict::ChangesUnknown,
+            Some(Ok(changes)) if !changes.is_empty() => Verdict::UnsnapshottedChanges {
+                changes: changes.clone(),
+            },
+            _ => Verdict::Ready,
+        }
+    };
+
+    Ok(ReadinessReport {
+        dataset: dataset.name.clone(),
+        owner: dataset.server.clone(),
+        servers,
+        changes: changes.and_then(Result::ok),
+        verdict,
+    })
+}
+
+pub async fn readiness(
+    req: ReadinessRequest,
+    config: ConfigRef,
+    connector: ConnectorRef,
+) -> warp::reply::Json {
+    let config = { config.read().await.clone() };
+    let Some(dataset) = config
+        .datasets
+        .iter()
+        .find(|ds: &&Dataset| ds.name == req.dataset)
+    else {
+        return warp::reply::json(&ErrorCode::DatasetNotFoundInConfig {
+            dataset: req.dataset.clone(),
+        });
+    };
+    match check(&config, &connector, dataset).await {
+        Ok(report) => warp::reply::json(&report),
+        Err(e) => warp::reply::json(&e),
+    }
+}
@@ -0,0 +1,29 @@
+use brig_common::api::switch::{CancelSwitchRequest, ScheduleSwitchRequest};
+
+use crate::{ConfigRef, Schedule, api::switch};
+
+pub async fn schedule(
+    req: ScheduleSwitchRequest,
+    config: ConfigRef,
+    schedule: Schedule,
+) -> warp::reply::Json {
+    // catch unknown datasets and servers now rather than in the middle of the night
+    if let Err(e) = switch::plan_switch(&*config.read().await, &req.switch) {
+        return warp::reply::json(&e);
+    }
+    match schedule.add(req.switch, req.at, req.deadline).await {
+        Ok(scheduled) => warp::reply::json(&scheduled),
+        Err(e) => warp::reply::json(&e),
+    }
+}
+
+pub async fn scheduled(schedule: Schedule) -> warp::reply::Json {
+    warp::reply::json(&schedule.list().await)
+}
+
+pub async fn cancel(req: CancelSwitchRequest, schedule: Schedule) -> warp::reply::Json {
+    match schedule.cancel(req.id).await {
+        Ok(cancelled) => warp::reply::json(&cancelled),
+        Err(e) => warp::reply::json(&e),
+    }
+}
@@ -0,0 +1,14 @@
+use crate::{ConfigRef, ServerHealths};
+
+/// The latest probe of every server, in config order. Servers that haven't
+/// been probed since brig started aren't listed yet.
+pub async fn servers(config: ConfigRef, healths: ServerHealths) -> warp::reply::Json {
+    let config = { config.read().await.clone() };
+    let healths = healths.read().await;
+    let servers: Vec<_> = config
+        .servers
+        .iter()
+        .filter_map(|server| healths.get(&server.name))
+        .collect();
+    warp::reply::json(&servers)
+}
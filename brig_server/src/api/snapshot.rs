@@ -0,0 +1,156 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use brig_common::api::{
+    api::ErrorCode,
+    snapshot::{PinnedSnapshots, SnapshotRequest, SnapshotResponse, UnpinRequest},
+};
+
+use crate::{
+    ConfigRef, ConnectorRef, Jobs, Leases, SyncStates,
+    api::{ownership, sync},
+    config::{dataset::Dataset, naming, server::Server},
+    zfs,
+};
+
+async fn take_labeled_snapshot(
+    req: &SnapshotRequest,
+    config_path: &Path,
+    config_arc: &ConfigRef,
+    states: &SyncStates,
+    jobs: &Jobs,
+    leases: &Leases,
+    connector: &ConnectorRef,
+) -> Result<String, ErrorCode> {
+    if !naming::is_valid_name_segment(&req.label) {
+        return Err(ErrorCode::InvalidSnapshotLabel {
+            label: req.label.clone(),
+        });
+    }
+
+    let lease = leases
+        .acquire(&req.dataset, "snapshot", req.wait.map(Duration::from_secs))
+        .await?;
+    let lease = Arc::new(lease);
+    // read with the lease held, a switch it waited for may have changed the
+    // owner
+    let config = { config_arc.read().await.clone() };
+    let dataset = config
+        .datasets
+        .iter()
+        .find(|ds: &&Dataset| ds.name == req.dataset)
+        .ok_or(ErrorCode::DatasetNotFoundInConfig {
+            dataset: req.dataset.clone(),
+        })?;
+    let src_server = config
+        .servers
+        .iter()
+        .find(|server: &&Server| server.name == dataset.server)
+        .ok_or(ErrorCode::ServerNotFoundFromDataset {
+            dataset: dataset.name.clone(),
+            server_name: dataset.server.clone(),
+        })?;
+
+    if req.replicate {
+        ownership::ensure_single_writer(&config, connector, dataset).await?;
+    }
+
+    let snapshot =
+        sync::take_snapshot(&config, connector, src_server, dataset, Some(&req.label)).await?;
+
+    if req.pinned {
+        let mut config = config_arc.write().await;
+        let dataset = config
+            .datasets
+            .iter_mut()
+            .find(|ds: &&mut Dataset| ds.name == req.dataset)
+            .ok_or(ErrorCode::DatasetNotFoundInConfig {
+                dataset: req.dataset.clone(),
+            })?;
+        dataset
+            .pinned_snapshots
+            .push(zfs::short_name(&snapshot).to_owned());
+        config.save(config_path)?;
+    }
+
+    if req.replicate {
+        for transfer in sync::replica_transfers(&config, connector, src_server, dataset, &snapshot)
+        {
+            sync::spawn_sync(transfer, states, jobs, lease.clone()).await;
+        }
+    }
+
+    Ok(snapshot)
+}
+
+pub async fn snapshot(
+    req: SnapshotRequest,
+    config_path: Arc<PathBuf>,
+    config: ConfigRef,
+    states: SyncStates,
+    jobs: Jobs,
+    leases: Leases,
+    connector: ConnectorRef,
+) -> warp::reply::Json {
+    match take_labeled_snapshot(
+        &req,
+        &config_path,
+        &config,
+        &states,
+        &jobs,
+        &leases,
+        &connector,
+    )
+    .await
+    {
+        Ok(snapshot) => warp::reply::json(&SnapshotResponse { snapshot }),
+        Err(e) => warp::reply::json(&e),
+    }
+}
+
+pub async fn pinned(config: ConfigRef) -> warp::reply::Json {
+    let config = config.read().await;
+    let pinned: Vec<PinnedSnapshots> = config
+        .datasets
+        .iter()
+        .map(|dataset| PinnedSnapshots {
+            dataset: dataset.name.clone(),
+            snapshots: dataset.pinned_snapshots.clone(),
+        })
+        .collect();
+    warp::reply::json(&pinned)
+}
+
+pub async fn unpin(
+    req: UnpinRequest,
+    config_path: Arc<PathBuf>,
+    config: ConfigRef,
+) -> warp::reply::Json {
+    let mut config = config.write().await;
+    let Some(dataset) = config
+        .datasets
+        .iter_mut()
+        .find(|ds: &&mut Dataset| ds.name == req.dataset)
+    else {
+        return warp::reply::json(&ErrorCode::DatasetNotFoundInConfig {
+            dataset: req.dataset.clone(),
+        });
+    };
+
+    let snapshot = zfs::short_name(&req.snapshot);
+    let Some(pos) = dataset.pinned_snapshots.iter().position(|s| s == snapshot) else {
+        return warp::reply::json(&ErrorCode::SnapshotNotPinned {
+            dataset: req.dataset.clone(),
+            snapshot: req.snapshot.clone(),
+        });
+    };
+    dataset.pinned_snapshots.remove(pos);
+
+    if let Err(e) = config.save(&config_path) {
+        return warp::reply::json(&e);
+    }
+    warp::reply::json(&())
+}
@@ -1,65 +1,57 @@
-use crate::ConfigRef;
+use crate::{ConfigRef, ConnectorRef, api::ownership, zfs};
 use brig_common::api::api::{Dataset, Datasets};
-use openssh::{KnownHosts, Session};
 
-async fn update_sessions(config: ConfigRef) -> Vec<Datasets> {
+async fn update_sessions(config: ConfigRef, connector: ConnectorRef) -> Vec<Datasets> {
     let config = config.read().await;
     let mut response = vec![];
 
     for server in &config.servers {
-        let session = Session::connect(
-            format!("{}@{}", server.user, server.address),
-            KnownHosts::Strict,
-        )
-        .await
-        .unwrap();
-
-        let ls = session
-            .command("zfs")
-            .arg("list")
-            .arg("-t")
-            .arg("snapshot")
-            .arg("-o")
-            .arg("name")
-            .output()
-            .await
-            .unwrap();
-
-        let output = String::from_utf8(ls.stdout).expect("server output was not valid UTF-8");
-
-        let datasets: Vec<&str> = output
-            .lines()
-            .skip(1)
-            .map(str::trim)
-            .filter(|line| !line.is_empty())
-            .collect();
-
         let mut ds = Datasets {
             server: server.address.clone(),
             datasets: vec![],
+            writable: vec![],
+        };
+        let snapshots = match connector.connect(server).await {
+            Ok(session) => zfs::all_snapshots(&session).await,
+            Err(e) => Err(e),
         };
+        let snapshots = match snapshots {
+            Ok(snapshots) => snapshots,
+            Err(e) => {
+                println!("failed to list snapshots on {}: {:?}", &server.name, &e);
+                response.push(ds);
+                continue;
+            }
+        };
+
         println!("Datasets on {}:", &ds.server);
-        for dataset in &datasets {
+        for snapshot in &snapshots {
             ds.datasets.push(Dataset {
-                pool: dataset.split_once('/').unwrap().0.to_string(),
-                dataset: dataset
-                    .split_once('/')
-                    .unwrap()
-                    .1
-                    .split_once('@')
-                    .unwrap()
-                    .0
-                    .to_string(),
-                snapshot: dataset.split_once('@').unwrap().1.to_string(),
+                pool: snapshot.pool().to_owned(),
+                dataset: snapshot.dataset().to_owned(),
+                snapshot: snapshot.short_name().to_owned(),
+                guid: snapshot.guid,
+                creation: snapshot.creation,
+                used: snapshot.used,
+                referenced: snapshot.referenced,
+                written: snapshot.written,
             });
-            println!("  {}", dataset);
+            println!("  {}", &snapshot.name);
         }
         response.push(ds);
     }
+
+    for ownership in ownership::check_all(&config, &connector).await {
+        for (server, ds) in config.servers.iter().zip(response.iter_mut()) {
+            if ownership.writable_on.contains(&server.name) {
+                ds.writable.push(ownership.dataset.clone());
+            }
+        }
+    }
     response
 }
 
-pub async fn status(config: ConfigRef) -> warp::reply::Json {
-    let res = update_sessions(config).await;
+pub async fn status(config: ConfigRef, connector: ConnectorRef) -> warp::reply::Json {
+    let res = update_sessions(config, connector).await;
     warp::reply::json(&res)
 }
@@ -1,163 +1,505 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-use brig_common::api::{api::ErrorCode, switch::SwitchRequest};
+use brig_common::api::{
+    api::ErrorCode,
+    switch::{SwitchReport, SwitchRequest, Verdict},
+};
+use chrono::Utc;
 
 use crate::{
-    ConfigRef,
-    config::{dataset::Dataset, server::Server},
-    utils,
+    ConfigRef, ConnectorRef, JobRef, Jobs, Leases, SyncStates,
+    api::{readiness, sync},
+    config::{
+        config::Config, dataset::Dataset, mount::MountRole, naming::SnapshotOrigin, server::Server,
+    },
+    exec::ExecutorRef,
+    job,
+    lease::Lease,
+    mount,
+    schedule::ScheduledSwitch,
+    switch_transaction::{Compensation, SwitchTransaction},
+    utils, zfs,
 };
 
-async fn switch_dataset(
-    config: ConfigRef,
-    dataset: String,
+/// The dataset being switched and the servers ownership moves between
+pub struct SwitchPlan {
+    request: SwitchRequest,
+    dataset: Dataset,
     old_server: Server,
     new_server: Server,
-) -> Result<(), ErrorCode> {
-    let old_session = utils::create_ssh_session(&old_server.user, &old_server.address).await?;
-    let new_session = utils::create_ssh_session(&new_server.user, &new_server.address).await?;
-
-    utils::set_readonly(&old_session, &old_server, &dataset, true).await?;
-    utils::set_readonly(&new_session, &new_server, &dataset, false).await?;
+}
 
-    let mut config = config.write().await;
-    let dataset = config
+/// Makes `server` the owner of `dataset` in the config and persists it,
+/// leaving the config untouched if it can't be written.
+pub async fn set_owner(
+    config_path: &Path,
+    config_arc: &ConfigRef,
+    dataset: &str,
+    server: &str,
+) -> Result<(), ErrorCode> {
+    let mut config = config_arc.write().await;
+    let pos = config
         .datasets
-        .iter_mut()
-        .find(|ds: &&mut Dataset| ds.name == dataset)
-        .ok_or(ErrorCode::DatasetNotFoundInConfig { dataset })?;
-    dataset.server = new_server.name;
+        .iter()
+        .position(|ds: &Dataset| ds.name == dataset)
+        .ok_or(ErrorCode::DatasetNotFoundInConfig {
+            dataset: dataset.to_owned(),
+        })?;
+    let previous = std::mem::replace(&mut config.datasets[pos].server, server.to_owned());
+    if let Err(e) = config.save(config_path) {
+        config.datasets[pos].server = previous;
+        return Err(e);
+    }
     Ok(())
 }
 
-async fn is_synced(dataset: &Dataset, config: ConfigRef) -> Result<bool, ErrorCode> {
-    // ensure all servers have the same latest snapshot
-    let mut latest_snapshots = vec![];
-    for server in &config.read().await.servers {
-        let session = utils::create_ssh_session(&server.user, &server.address).await?;
-        let latest = utils::get_latest_snapshot(&session, &server.pool, &dataset.name).await?;
-        latest_snapshots.push(latest)
+async fn run_hooks(
+    tx: &mut SwitchTransaction,
+    session: &ExecutorRef,
+    server: &Server,
+    stage: &str,
+    commands: &[String],
+) -> Result<(), ErrorCode> {
+    for command in commands {
+        let (result, output) = match utils::run_shell(session, server, command).await {
+            Ok((Some(0), output)) => (Ok(()), Some(output)),
+            Ok((status, output)) => (
+                Err(ErrorCode::HookFailed {
+                    server: server.name.clone(),
+                    command: command.clone(),
+                    exit_code: status,
+                    output: output.clone(),
+                }),
+                Some(output),
+            ),
+            Err(e) => (Err(e), None),
+        };
+        tx.hook_step(
+            format!("run {} hook on {}: {}", stage, &server.name, command),
+            result,
+            output,
+        )
+        .await?;
     }
+    Ok(())
+}
 
-    if let Some(first) = latest_snapshots.first() {
-        if !latest_snapshots.iter().all(|s| s == first) {
-            return Ok(false);
-        }
-    } else {
-        return Ok(false);
+/// Runs the steps of a switch, recording each in `tx`. A planned switch first
+/// quiesces the old owner and sends a final snapshot to the new owner (and, if
+/// `replicate_all`, every other replica), checking it arrived before the new
+/// owner is made writable. The dataset's hooks run around demoting the old
+/// owner and promoting the new one.
+async fn switch_dataset(
+    tx: &mut SwitchTransaction,
+    plan: &SwitchPlan,
+    config_path: &Path,
+    config_arc: &ConfigRef,
+    states: &SyncStates,
+    lease: &Lease,
+) -> Result<(), ErrorCode> {
+    let config = { config_arc.read().await.clone() };
+    let connector = tx.connector().clone();
+    let SwitchPlan {
+        request: req,
+        dataset,
+        old_server,
+        new_server,
+    } = plan;
+
+    let old_session = tx
+        .step(
+            format!("connect to {}", &old_server.name),
+            connector.connect(old_server).await,
+            Compensation::None,
+        )
+        .await?;
+    let new_session = tx
+        .step(
+            format!("connect to {}", &new_server.name),
+            connector.connect(new_server).await,
+            Compensation::None,
+        )
+        .await?;
+    let old_readonly = tx
+        .step(
+            format!("read readonly on {}", &old_server.name),
+            utils::get_readonly(&old_session, old_server, &dataset.name).await,
+            Compensation::None,
+        )
+        .await?;
+    let new_readonly = tx
+        .step(
+            format!("read readonly on {}", &new_server.name),
+            utils::get_readonly(&new_session, new_server, &dataset.name).await,
+            Compensation::None,
+        )
+        .await?;
+
+    run_hooks(
+        tx,
+        &old_session,
+        old_server,
+        "pre-demote",
+        &dataset.hooks.pre_demote,
+    )
+    .await?;
+    tx.step(
+        format!("set read-only on {}", &old_server.name),
+        utils::set_readonly(&old_session, old_server, &dataset.name, true).await,
+        Compensation::SetReadonly {
+            server: old_server.clone(),
+            readonly: old_readonly,
+        },
+    )
+    .await?;
+    if let Some(policy) = &dataset.mount {
+        tx.step(
+            format!("unmount on {}", &old_server.name),
+            mount::enforce(
+                &old_session,
+                old_server,
+                dataset,
+                policy,
+                MountRole::Replica,
+            )
+            .await,
+            Compensation::EnforceMount {
+                server: old_server.clone(),
+                dataset: Box::new(dataset.clone()),
+                policy: *policy,
+                role: MountRole::Owner,
+            },
+        )
+        .await?;
     }
+    run_hooks(
+        tx,
+        &old_session,
+        old_server,
+        "post-demote",
+        &dataset.hooks.post_demote,
+    )
+    .await?;
 
-    // if they all have the same latest, make sure owning server doesn't have a zfs diff
-    let config = { config.read().await.clone() };
-    let server = config
-        .servers
-        .iter()
-        .find(|s: &&Server| s.name == dataset.server)
-        .ok_or(ErrorCode::ServerNotFoundFromDataset {
-            dataset: dataset.name.clone(),
-            server_name: dataset.server.clone(),
-        })?;
+    if req.planned {
+        let label = config
+            .snapshot_naming
+            .origin_label(SnapshotOrigin::PreSwitch);
+        let snapshot = tx
+            .step(
+                format!("take final snapshot on {}", &old_server.name),
+                sync::take_snapshot(&config, &connector, old_server, dataset, label).await,
+                Compensation::None,
+            )
+            .await?;
 
-    let session = utils::create_ssh_session(&server.user, &server.address).await?;
-
-    if let Some(latest_snapshot) = latest_snapshots.first() {
-        let output = session
-            .command("zfs")
-            .arg("diff")
-            .arg(format!("{}", latest_snapshot))
-            .output()
-            .await
-            .map_err(|_| ErrorCode::ZfsCommandError {
-                msg: format!("unable to zfs diff {}", latest_snapshot)
-            })?;
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        if !stdout.is_empty() {
-            return Ok(false);
+        let targets: Vec<&Server> = config
+            .servers
+            .iter()
+            .filter(|server| {
+                server.name == new_server.name
+                    || (req.replicate_all && server.name != old_server.name)
+            })
+            .collect();
+        for &target in &targets {
+            let transfer = sync::Transfer {
+                config: config.clone(),
+                connector: connector.clone(),
+                src: old_server.clone(),
+                dst: target.clone(),
+                dataset: dataset.clone(),
+                snapshot: snapshot.clone(),
+            };
+            tx.step(
+                format!("replicate {} to {}", &snapshot, &target.name),
+                sync::replicate(transfer, states, lease).await,
+                Compensation::None,
+            )
+            .await?;
         }
-        return Ok(true);
-    } else {
-        return Ok(false);
+
+        for target in &targets {
+            let result = match connector.connect(target).await {
+                Ok(session) => zfs::latest_snapshot(&session, &target.pool, &dataset.name)
+                    .await
+                    .and_then(|latest| {
+                        if latest.short_name() == zfs::short_name(&snapshot) {
+                            Ok(())
+                        } else {
+                            Err(ErrorCode::DatasetNotSynced {
+                                dataset: dataset.name.clone(),
+                            })
+                        }
+                    }),
+                Err(e) => Err(e),
+            };
+            tx.step(
+                format!("verify {} on {}", &snapshot, &target.name),
+                result,
+                Compensation::None,
+            )
+            .await?;
+        }
+    }
+
+    run_hooks(
+        tx,
+        &new_session,
+        new_server,
+        "pre-promote",
+        &dataset.hooks.pre_promote,
+    )
+    .await?;
+    tx.step(
+        format!("set writable on {}", &new_server.name),
+        utils::set_readonly(&new_session, new_server, &dataset.name, false).await,
+        Compensation::SetReadonly {
+            server: new_server.clone(),
+            readonly: new_readonly,
+        },
+    )
+    .await?;
+    if let Some(policy) = &dataset.mount {
+        tx.step(
+            format!("mount on {}", &new_server.name),
+            mount::enforce(&new_session, new_server, dataset, policy, MountRole::Owner).await,
+            Compensation::EnforceMount {
+                server: new_server.clone(),
+                dataset: Box::new(dataset.clone()),
+                policy: *policy,
+                role: MountRole::Replica,
+            },
+        )
+        .await?;
     }
+
+    tx.step(
+        format!("make {} the owner in the config", &new_server.name),
+        set_owner(config_path, config_arc, &dataset.name, &new_server.name).await,
+        Compensation::SetOwner {
+            server: old_server.name.clone(),
+        },
+    )
+    .await?;
+    run_hooks(
+        tx,
+        &new_session,
+        new_server,
+        "post-promote",
+        &dataset.hooks.post_promote,
+    )
+    .await?;
+    Ok(())
 }
 
-pub async fn switch(
-    req: SwitchRequest,
-    config_path: Arc<PathBuf>,
-    config_arc: ConfigRef,
-) -> warp::reply::Json {
-    let config = { config_arc.read().await.clone() };
+async fn run_switch(
+    plan: &SwitchPlan,
+    config_path: &Path,
+    config_arc: &ConfigRef,
+    states: &SyncStates,
+    connector: &ConnectorRef,
+    job: Option<JobRef>,
+    lease: &Lease,
+) -> SwitchReport {
+    let mut tx = SwitchTransaction::new(
+        &plan.dataset,
+        &plan.old_server,
+        &plan.new_server,
+        connector,
+        job,
+    );
+    match switch_dataset(&mut tx, plan, config_path, config_arc, states, lease).await {
+        Ok(()) => tx.commit(),
+        Err(e) => tx.rollback(e, config_path, config_arc).await,
+    }
+}
+
+/// Looks up the dataset and the servers a switch moves it between
+pub fn plan_switch(config: &Config, req: &SwitchRequest) -> Result<SwitchPlan, ErrorCode> {
     let dataset = config
         .datasets
         .iter()
         .find(|ds: &&Dataset| ds.name == req.dataset)
-        .take();
-    if dataset.is_none() {
-        return warp::reply::json(&ErrorCode::DatasetNotFoundInConfig {
+        .ok_or(ErrorCode::DatasetNotFoundInConfig {
             dataset: req.dataset.clone(),
-        });
-    }
-    let dataset = dataset.unwrap();
-
-    match is_synced(dataset, config_arc.clone()).await {
-        Ok(is_synced) => {
-            if !is_synced {
-                return warp::reply::json(&ErrorCode::DatasetNotSynced {
-                    dataset: dataset.name.clone(),
-                });
-            }
-        }
-        Err(e) => {
-            return warp::reply::json(&e);
-        }
-    }
-
+        })?;
     let old_server = config
         .servers
         .iter()
-        .find(|server: &&Server| server.name == dataset.server);
-    if old_server.is_none() {
-        return warp::reply::json(&ErrorCode::ServerNotFoundFromDataset {
+        .find(|server: &&Server| server.name == dataset.server)
+        .ok_or(ErrorCode::ServerNotFoundFromDataset {
             dataset: dataset.name.clone(),
             server_name: dataset.server.clone(),
-        });
-    }
-    let old_server = old_server.unwrap();
-
+        })?;
     let new_server = config
         .servers
         .iter()
-        .find(|server: &&Server| server.name == req.new_server);
-    if new_server.is_none() {
-        return warp::reply::json(&ErrorCode::ServerNotFoundFromRequest {
+        .find(|server: &&Server| server.name == req.new_server)
+        .ok_or(ErrorCode::ServerNotFoundFromRequest {
             server_name: req.new_server.clone(),
+        })?;
+    if new_server.name == old_server.name {
+        return Err(ErrorCode::AlreadyOwner {
+            dataset: dataset.name.clone(),
+            server: new_server.name.clone(),
+        });
+    }
+    Ok(SwitchPlan {
+        request: req.clone(),
+        dataset: dataset.clone(),
+        old_server: old_server.clone(),
+        new_server: new_server.clone(),
+    })
+}
+
+/// The new owner must already hold a replica with snapshots to take over from
+async fn ensure_replica(connector: &ConnectorRef, plan: &SwitchPlan) -> Result<(), ErrorCode> {
+    let not_on_server = || ErrorCode::DatasetNotOnServer {
+        dataset: plan.dataset.name.clone(),
+        server: plan.new_server.name.clone(),
+    };
+    let session = connector.connect(&plan.new_server).await?;
+    match zfs::snapshots(&session, &plan.new_server.pool, &plan.dataset.name).await {
+        Ok(snapshots) if snapshots.is_empty() => Err(not_on_server()),
+        Ok(_) => Ok(()),
+        // zfs fails to list a dataset that doesn't exist
+        Err(ErrorCode::ZfsCommandError {
+            exit_code: Some(_), ..
+        }) => Err(not_on_server()),
+        Err(e) => Err(e),
+    }
+}
+
+/// An unplanned switch only goes ahead if every server already has the
+/// owner's latest snapshot and nothing was written since
+async fn ensure_ready(
+    config: &Config,
+    connector: &ConnectorRef,
+    dataset: &Dataset,
+) -> Result<(), ErrorCode> {
+    let report = readiness::check(config, connector, dataset).await?;
+    if !matches!(report.verdict, Verdict::Ready) {
+        println!(
+            "dataset {} is not ready to switch: {:?}",
+            &dataset.name, &report.verdict
+        );
+        return Err(ErrorCode::DatasetNotSynced {
+            dataset: dataset.name.clone(),
         });
     }
-    let new_server = new_server.unwrap();
+    Ok(())
+}
 
-    if let Err(e) = switch_dataset(
-        config_arc.clone(),
-        req.dataset,
-        old_server.clone(),
-        new_server.clone(),
+/// Runs a switch that was scheduled ahead of time as `job`, waiting for the
+/// dataset's lease until its deadline at the latest
+pub async fn scheduled_switch(
+    due: &ScheduledSwitch,
+    config_path: &Path,
+    config_arc: &ConfigRef,
+    states: &SyncStates,
+    leases: &Leases,
+    connector: &ConnectorRef,
+    job: JobRef,
+) -> Result<(), ErrorCode> {
+    let req = &due.request;
+    let wait = (due.deadline - Utc::now()).to_std().unwrap_or_default();
+    let lease = leases
+        .acquire(&req.dataset, "scheduled switch", Some(wait))
+        .await?;
+    // planned with the lease held, the wait for it can last until the
+    // deadline
+    let config = { config_arc.read().await.clone() };
+    let plan = plan_switch(&config, req)?;
+    ensure_replica(connector, &plan).await?;
+    if !req.planned {
+        ensure_ready(&config, connector, &plan.dataset).await?;
+    }
+    let report = run_switch(
+        &plan,
+        config_path,
+        config_arc,
+        states,
+        connector,
+        Some(job),
+        &lease,
     )
-    .await
+    .await;
+    match report.error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+pub async fn switch(
+    req: SwitchRequest,
+    config_path: Arc<PathBuf>,
+    config_arc: ConfigRef,
+    states: SyncStates,
+    jobs: Jobs,
+    leases: Leases,
+    connector: ConnectorRef,
+) -> warp::reply::Json {
+    let lease = match leases
+        .acquire(&req.dataset, "switch", req.wait.map(Duration::from_secs))
+        .await
     {
+        Ok(lease) => lease,
+        Err(e) => return warp::reply::json(&e),
+    };
+    // planned with the lease held, a switch it waited for may have changed
+    // the owner
+    let config = { config_arc.read().await.clone() };
+    let plan = match plan_switch(&config, &req) {
+        Ok(plan) => plan,
+        Err(e) => return warp::reply::json(&e),
+    };
+    let dataset = &plan.dataset;
+    if let Err(e) = ensure_replica(&connector, &plan).await {
         return warp::reply::json(&e);
     }
 
-    let json_str = serde_json::to_string_pretty(&*config_arc.read().await)
-        .map_err(|_| ErrorCode::ConfigIsInvalidJson);
-    if let Err(e) = json_str {
-        return warp::reply::json(&e);
+    if req.planned {
+        let job = job::start_job(&jobs, "planned switch", &dataset.name).await;
+        tokio::spawn({
+            let job = job.clone();
+            async move {
+                let report = run_switch(
+                    &plan,
+                    &config_path,
+                    &config_arc,
+                    &states,
+                    &connector,
+                    Some(job.clone()),
+                    &lease,
+                )
+                .await;
+                let result = match report.error {
+                    Some(e) => Err(e),
+                    None => Ok(()),
+                };
+                job::finish_job(&job, result).await;
+            }
+        });
+        return warp::reply::json(&*job.read().await);
     }
-    let json_str = json_str.unwrap();
-    if let Err(e) =
-        std::fs::write(&*config_path, &json_str).map_err(|_| ErrorCode::ErrorWritingConfigFile {
-            path: (&*config_path).clone(),
-        })
-    {
+
+    if let Err(e) = ensure_ready(&config, &connector, dataset).await {
         return warp::reply::json(&e);
     }
 
-    warp::reply::json(&())
+    let report = run_switch(
+        &plan,
+        &config_path,
+        &config_arc,
+        &states,
+        &connector,
+        None,
+        &lease,
+    )
+    .await;
+    warp::reply::json(&report)
 }
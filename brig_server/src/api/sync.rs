@@ -1,34 +1,66 @@
 use brig_common::api::{api::ErrorCode, sync::SyncRequest};
-use std::sync::Arc;
-use tokio::
-    sync::{Barrier, RwLock}
-;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{RwLock, oneshot};
 
 use crate::{
-    ConfigRef, SyncStateRef, SyncStates,
+    ConfigRef, ConnectorRef, Jobs, Leases, SyncStateRef, SyncStates,
+    api::{clean, ownership},
     config::{
-        dataset::{self, Dataset},
-        server::Server,
+        config::Config, dataset::Dataset, mount::MountRole, naming::SnapshotOrigin, server::Server,
     },
+    exec::ExecutorRef,
+    job,
+    lease::Lease,
+    mount,
     sync_state::SyncState,
     utils,
+    zfs::{self, Snapshot},
 };
 
+/// One transfer of a dataset from its owner to a replica, up to and
+/// including `snapshot`
+pub struct Transfer {
+    pub config: Config,
+    pub connector: ConnectorRef,
+    pub src: Server,
+    pub dst: Server,
+    pub dataset: Dataset,
+    pub snapshot: String,
+}
+
 async fn sync_dataset(
+    transfer: Transfer,
+    lease: &Lease,
     state: SyncStateRef,
-    states: SyncStates,
-    src: Server,
-    dst: Server,
-    dataset: dataset::Dataset,
-    http_return_barrier: Arc<Barrier>,
+    size_known: oneshot::Sender<()>,
 ) -> Result<(), ErrorCode> {
-    let src_session = utils::create_ssh_session(&src.user, &src.address).await?;
-    let dst_session = utils::create_ssh_session(&dst.user, &dst.address).await?;
-    let src_snapshots = utils::list_snapshots(&src_session, &src.pool, &dataset.name).await?;
-    let dst_snapshots = utils::list_snapshots(&dst_session, &dst.pool, &dataset.name).await?;
+    let Transfer {
+        config,
+        connector,
+        src,
+        dst,
+        dataset,
+        snapshot: new_snapshot,
+    } = transfer;
+    // `zfs recv -F` would destroy what the old owner never replicated
+    if dataset.fenced_servers.contains(&dst.name) {
+        return Err(ErrorCode::FailoverNotAcknowledged {
+            dataset: dataset.name.clone(),
+            server: dst.name.clone(),
+        });
+    }
+    let src_session = connector.connect(&src).await?;
+    let dst_session = connector.connect(&dst).await?;
+    let src_snapshots = zfs::snapshots(&src_session, &src.pool, &dataset.name).await?;
+    let dst_snapshots = zfs::snapshots(&dst_session, &dst.pool, &dataset.name).await?;
     let latest_common_snapshot =
         utils::find_latest_common_snapshot(&dataset.name, &src_snapshots, &dst_snapshots).await?;
-    let new_snapshot = utils::create_snapshot(&src_session, &src.pool, &dataset.name).await?;
+    if latest_common_snapshot.short_name() == zfs::short_name(&new_snapshot) {
+        // dst already has it
+        let _ = size_known.send(());
+        return Ok(());
+    }
+    let latest_common_snapshot = latest_common_snapshot.name;
     let total_bytes =
         utils::estimate_send_size(&src_session, &latest_common_snapshot, &new_snapshot).await?;
     {
@@ -36,9 +68,21 @@ async fn sync_dataset(
         state.total_bytes = total_bytes;
     }
 
-    http_return_barrier.wait().await;
+    let _ = size_known.send(());
+
+    if let Err(e) = clean::prune_for_space(
+        &config,
+        &connector,
+        lease.leases(),
+        &dst,
+        Some(lease.dataset()),
+    )
+    .await
+    {
+        println!("failed to free up space on {}: {:?}", &dst.name, &e);
+    }
 
-    utils::send_bytes(
+    let sent_bytes = utils::send_bytes(
         &src_session,
         &dst_session,
         &latest_common_snapshot,
@@ -49,81 +93,218 @@ async fn sync_dataset(
     )
     .await?;
 
-    let mut states = states.write().await;
-    let mut pos = None;
-    for (i, other) in states.iter().enumerate() {
-        if other.read().await.dataset == dataset.name {
-            pos = Some(i);
-            break;
-        }
+    let received = Received {
+        src_snapshots: &src_snapshots,
+        snapshot: &new_snapshot,
+        estimated_bytes: total_bytes,
+        sent_bytes,
+    };
+    verify_received(&dst_session, &dst, &dataset, received).await?;
+
+    // the replica has the data either way, so a mount that fails doesn't
+    // fail the transfer
+    let Some(policy) = &dataset.mount else {
+        return Ok(());
+    };
+    if let Err(e) = mount::enforce(&dst_session, &dst, &dataset, policy, MountRole::Replica).await {
+        println!(
+            "failed to enforce the mount policy of {} on {}: {:?}",
+            &dataset.name, &dst.name, &e
+        );
+    }
+    Ok(())
+}
+
+/// What was sent to a replica, to check it against what the replica has
+struct Received<'a> {
+    src_snapshots: &'a [Snapshot],
+    snapshot: &'a str,
+    estimated_bytes: u64,
+    sent_bytes: u64,
+}
+
+/// Checks that `dst` has the snapshot that was sent with the guid it has on the
+/// source. `zfs send -n` only estimates the size of the stream, so a stream
+/// more than 10% (or 1 MiB) off is only logged, unless it's less than half
+/// the estimate, which suggests it ended short.
+async fn verify_received(
+    dst_session: &ExecutorRef,
+    dst: &Server,
+    dataset: &Dataset,
+    received: Received<'_>,
+) -> Result<(), ErrorCode> {
+    let failed = |reason: String| ErrorCode::ReplicaVerificationFailed {
+        dataset: dataset.name.clone(),
+        server: dst.name.clone(),
+        snapshot: received.snapshot.to_owned(),
+        reason,
+    };
+    let expected = received
+        .src_snapshots
+        .iter()
+        .find(|snapshot| snapshot.name == received.snapshot)
+        .ok_or_else(|| failed("the source doesn't have the snapshot".to_owned()))?;
+    let dst_snapshots = zfs::snapshots(dst_session, &dst.pool, &dataset.name).await?;
+    let actual = dst_snapshots
+        .iter()
+        .find(|snapshot| snapshot.short_name() == expected.short_name())
+        .ok_or_else(|| failed("the snapshot wasn't received".to_owned()))?;
+    if actual.guid != expected.guid {
+        return Err(failed(format!(
+            "received guid {} but the source has {}",
+            actual.guid, expected.guid
+        )));
+    }
+
+    let tolerance = (received.estimated_bytes / 10).max(1 << 20);
+    if received.sent_bytes.abs_diff(received.estimated_bytes) <= tolerance {
+        return Ok(());
     }
-    if let Some(pos) = pos {
-        states.remove(pos);
+    let mismatch = format!(
+        "sent {} bytes but {} were estimated",
+        received.sent_bytes, received.estimated_bytes
+    );
+    if received.sent_bytes < received.estimated_bytes / 2 {
+        return Err(failed(mismatch));
     }
+    println!(
+        "{} to {}: {}, the guid matches so the transfer is kept",
+        received.snapshot, &dst.name, mismatch
+    );
     Ok(())
 }
 
-pub async fn sync_all(config: ConfigRef, states: SyncStates) -> warp::reply::Json {
-    let mut states_in_progress = vec![];
-    let config = config.read().await;
-    let mut http_return_barriers = vec![];
-    for dataset in &config.datasets {
-        let mut is_in_progress = false;
-
-        for state in &*states.read().await {
-            if state.read().await.dataset == dataset.name {
-                states_in_progress.push(state.clone());
-                is_in_progress = true;
-                break;
-            }
-        }
+/// Takes a new snapshot of `dataset` on `src`, to be replicated to the other servers
+pub async fn take_snapshot(
+    config: &Config,
+    connector: &ConnectorRef,
+    src: &Server,
+    dataset: &Dataset,
+    label: Option<&str>,
+) -> Result<String, ErrorCode> {
+    let session = connector.connect(src).await?;
+    utils::create_snapshot(
+        &session,
+        &src.pool,
+        &dataset.name,
+        &config.snapshot_naming,
+        label,
+    )
+    .await
+}
 
-        if is_in_progress {
-            println!("dataset {} is already in progress", &dataset.name);
-            continue;
-        }
+async fn track_sync(states: &SyncStates, transfer: &Transfer, job: Option<u64>) -> SyncStateRef {
+    let state = Arc::new(RwLock::new(SyncState {
+        dataset: transfer.dataset.name.clone(),
+        src: transfer.src.name.clone(),
+        dst: transfer.dst.name.clone(),
+        total_bytes: 0,
+        sent_bytes: 0,
+        job,
+    }));
+    states.write().await.push(state.clone());
+    state
+}
 
-        let src_server = config
-            .servers
-            .iter()
-            .find(|server: &&Server| server.name == dataset.server)
-            .unwrap();
+/// Runs `transfer` and waits for it to finish. The caller must hold `lease`
+/// on the dataset.
+pub async fn replicate(
+    transfer: Transfer,
+    states: &SyncStates,
+    lease: &Lease,
+) -> Result<(), ErrorCode> {
+    let state = track_sync(states, &transfer, None).await;
+    let (size_known, _) = oneshot::channel();
+    let result = sync_dataset(transfer, lease, state.clone(), size_known).await;
+    states
+        .write()
+        .await
+        .retain(|other| !Arc::ptr_eq(other, &state));
+    result
+}
 
-        for dst_server in &config.servers {
-            if src_server.name == dst_server.name {
-                continue;
-            }
-            let state = Arc::new(RwLock::new(SyncState::default()));
-            state.write().await.dataset = dataset.name.clone();
-            state.write().await.src = src_server.name.clone();
-            state.write().await.dst = dst_server.name.clone();
-            state.write().await.total_bytes = 0;
-            state.write().await.sent_bytes = 0;
-
-            let http_return_barrier = Arc::new(Barrier::new(2));
-            http_return_barriers.push(http_return_barrier.clone());
-            tokio::spawn(sync_dataset(
-                state.clone(),
-                states.clone(),
-                src_server.clone(),
-                dst_server.clone(),
-                dataset.clone(),
-                http_return_barrier,
-            ));
-            states_in_progress.push(state.clone());
-            states.write().await.push(state);
+/// Starts `transfer` in the background and tracks it in `states` until it
+/// finishes, and as a job in `jobs` that keeps its outcome. The lease on the
+/// dataset is held until then. The returned receiver completes once the
+/// transfer size is known, or with an error if the sync failed before that.
+pub async fn spawn_sync(
+    transfer: Transfer,
+    states: &SyncStates,
+    jobs: &Jobs,
+    lease: Arc<Lease>,
+) -> (SyncStateRef, oneshot::Receiver<()>) {
+    let job = job::start_job(jobs, "sync", &transfer.dataset.name).await;
+    let id = job.read().await.id;
+    let state = track_sync(states, &transfer, Some(id)).await;
+    let (size_known, size_known_rx) = oneshot::channel();
+
+    tokio::spawn({
+        let state = state.clone();
+        let states = states.clone();
+        async move {
+            job::log_step(
+                &job,
+                format!(
+                    "replicate {} from {} to {}",
+                    &transfer.snapshot, &transfer.src.name, &transfer.dst.name
+                ),
+            )
+            .await;
+            let result = sync_dataset(transfer, &lease, state.clone(), size_known).await;
+            job::finish_job(&job, result).await;
+
+            let mut states = states.write().await;
+            states.retain(|other| !Arc::ptr_eq(other, &state));
         }
-    }
+    });
 
-    let mut handles = vec![];
+    (state, size_known_rx)
+}
 
-    for barrier in http_return_barriers {
-        let barrier = barrier.clone();
-        handles.push(tokio::spawn(async move { barrier.wait().await }));
-    }
+/// The server `dataset` is synced from
+fn owner<'a>(config: &'a Config, dataset: &Dataset) -> Result<&'a Server, ErrorCode> {
+    config
+        .servers
+        .iter()
+        .find(|server: &&Server| server.name == dataset.server)
+        .ok_or(ErrorCode::ServerNotFoundFromDataset {
+            dataset: dataset.name.clone(),
+            server_name: dataset.server.clone(),
+        })
+}
+
+/// A transfer of `snapshot` to every server other than the owner
+pub fn replica_transfers(
+    config: &Config,
+    connector: &ConnectorRef,
+    src_server: &Server,
+    dataset: &Dataset,
+    snapshot: &str,
+) -> Vec<Transfer> {
+    config
+        .servers
+        .iter()
+        .filter(|dst_server| dst_server.name != src_server.name)
+        .map(|dst_server| Transfer {
+            config: config.clone(),
+            connector: connector.clone(),
+            src: src_server.clone(),
+            dst: dst_server.clone(),
+            dataset: dataset.clone(),
+            snapshot: snapshot.to_owned(),
+        })
+        .collect()
+}
 
-    for handle in handles {
-        handle.await.unwrap();
+/// Waits until the size of every transfer is known and returns the state of
+/// all running syncs
+async fn sync_states(
+    states: &SyncStates,
+    sizes_known: Vec<oneshot::Receiver<()>>,
+) -> warp::reply::Json {
+    // a sync that failed early drops its sender instead of blocking the reply
+    for size_known in sizes_known {
+        let _ = size_known.await;
     }
 
     let mut states_to_return = vec![];
@@ -135,77 +316,125 @@ pub async fn sync_all(config: ConfigRef, states: SyncStates) -> warp::reply::Jso
     warp::reply::json(&states_to_return)
 }
 
-pub async fn sync(req: SyncRequest, config: ConfigRef, states: SyncStates) -> warp::reply::Json {
-    let config = config.read().await;
-    let mut http_return_barriers = vec![];
-    let mut is_in_progress = false;
-
-    for dataset in req.datasets {
-        let dataset = config
-            .datasets
-            .iter()
-            .find(|ds: &&Dataset| dataset == ds.name)
-            .unwrap();
-
-        for state in &*states.read().await {
-            if state.read().await.dataset == dataset.name {
-                is_in_progress = true;
-                break;
+pub async fn sync_all(
+    config_arc: ConfigRef,
+    states: SyncStates,
+    jobs: Jobs,
+    leases: Leases,
+    connector: ConnectorRef,
+) -> warp::reply::Json {
+    let names: Vec<String> = {
+        let config = config_arc.read().await;
+        config.datasets.iter().map(|ds| ds.name.clone()).collect()
+    };
+    let mut sizes_known = vec![];
+    for name in names {
+        let lease = match leases.acquire(&name, "sync", None).await {
+            Ok(lease) => Arc::new(lease),
+            Err(e) => {
+                println!("skipping dataset {}: {:?}", &name, &e);
+                continue;
             }
+        };
+        // read once the lease is held, so a switch that just finished is seen
+        let config = { config_arc.read().await.clone() };
+        let Some(dataset) = config.datasets.iter().find(|ds: &&Dataset| ds.name == name) else {
+            continue;
+        };
+        if let Err(e) = ownership::ensure_single_writer(&config, &connector, dataset).await {
+            println!("not syncing dataset {}: {:?}", &dataset.name, &e);
+            continue;
         }
 
-        if is_in_progress {
-            println!("dataset {} is already in progress", &dataset.name);
-            return warp::reply::json(&());
-        }
-
-        let src_server = config
-            .servers
-            .iter()
-            .find(|server: &&Server| server.name == dataset.server)
-            .unwrap();
+        let src_server = match owner(&config, dataset) {
+            Ok(src_server) => src_server,
+            Err(e) => {
+                println!("not syncing dataset {}: {:?}", &dataset.name, &e);
+                continue;
+            }
+        };
 
-        for dst_server in &config.servers {
-            if src_server.name == dst_server.name {
+        let label = config
+            .snapshot_naming
+            .origin_label(SnapshotOrigin::Scheduled);
+        let snapshot = match take_snapshot(&config, &connector, src_server, dataset, label).await {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                println!("failed to snapshot dataset {}: {:?}", &dataset.name, &e);
                 continue;
             }
-            let state = Arc::new(RwLock::new(SyncState::default()));
-            state.write().await.dataset = dataset.name.clone();
-            state.write().await.src = src_server.name.clone();
-            state.write().await.dst = dst_server.name.clone();
-            state.write().await.total_bytes = 0;
-            state.write().await.sent_bytes = 0;
-
-            let http_return_barrier = Arc::new(Barrier::new(2));
-            http_return_barriers.push(http_return_barrier.clone());
-            tokio::spawn(sync_dataset(
-                state.clone(),
-                states.clone(),
-                src_server.clone(),
-                dst_server.clone(),
-                dataset.clone(),
-                http_return_barrier,
-            ));
-            states.write().await.push(state);
+        };
+
+        for transfer in replica_transfers(&config, &connector, src_server, dataset, &snapshot) {
+            let (_, size_known) = spawn_sync(transfer, &states, &jobs, lease.clone()).await;
+            sizes_known.push(size_known);
         }
     }
 
-    let mut handles = vec![];
+    sync_states(&states, sizes_known).await
+}
 
-    for barrier in http_return_barriers {
-        let barrier = barrier.clone();
-        handles.push(tokio::spawn(async move { barrier.wait().await }));
+/// Syncs the requested datasets. Every lease is taken, ownership checked and
+/// snapshot taken before any transfer starts, so a request that fails hasn't
+/// started replicating part of its datasets.
+pub async fn sync(
+    req: SyncRequest,
+    config_arc: ConfigRef,
+    states: SyncStates,
+    jobs: Jobs,
+    leases: Leases,
+    connector: ConnectorRef,
+) -> warp::reply::Json {
+    // the config isn't held while waiting, a switch holding one of the
+    // leases has to write it before letting go
+    let wait = req.wait.map(Duration::from_secs);
+    let mut dataset_leases = vec![];
+    for name in &req.datasets {
+        match leases.acquire(name, "sync", wait).await {
+            Ok(lease) => dataset_leases.push(Arc::new(lease)),
+            Err(e) => return warp::reply::json(&e),
+        }
     }
+    let config = { config_arc.read().await.clone() };
 
-    for handle in handles {
-        handle.await.unwrap();
+    let mut datasets = vec![];
+    for name in &req.datasets {
+        let Some(dataset) = config
+            .datasets
+            .iter()
+            .find(|ds: &&Dataset| ds.name == *name)
+        else {
+            return warp::reply::json(&ErrorCode::DatasetNotFoundInConfig {
+                dataset: name.clone(),
+            });
+        };
+        if let Err(e) = ownership::ensure_single_writer(&config, &connector, dataset).await {
+            return warp::reply::json(&e);
+        }
+        match owner(&config, dataset) {
+            Ok(src_server) => datasets.push((dataset, src_server)),
+            Err(e) => return warp::reply::json(&e),
+        }
     }
 
-    let mut states_to_return = vec![];
-    for state in states.read().await.iter() {
-        let state = state.read().await.clone();
-        states_to_return.push(state);
+    let label = config.snapshot_naming.origin_label(SnapshotOrigin::Manual);
+    let mut snapshots = vec![];
+    for (dataset, src_server) in &datasets {
+        match take_snapshot(&config, &connector, src_server, dataset, label).await {
+            Ok(snapshot) => snapshots.push(snapshot),
+            Err(e) => return warp::reply::json(&e),
+        }
     }
 
-    warp::reply::json(&states_to_return)
+    let mut sizes_known = vec![];
+    for (((dataset, src_server), snapshot), lease) in
+        datasets.iter().zip(&snapshots).zip(dataset_leases)
+    {
+        for transfer in replica_transfers(&config, &connector, src_server, dataset, snapshot) {
+            let (_, size_known) = spawn_sync(transfer, &states, &jobs, lease.clone()).await;
+            sizes_known.push(size_known);
+        }
+    }
+
+    sync_states(&states, sizes_known).await
 }
@@ -0,0 +1,16 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+/// Where the brig_agent on a server listens, for the agent backend
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AgentOptions {
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// file holding the token the agent was started with
+    pub token_file: PathBuf,
+}
+
+fn default_port() -> u16 {
+    brig_common::agent::DEFAULT_PORT
+}
@@ -0,0 +1,132 @@
+use std::{
+    fs::{self, DirBuilder, File, OpenOptions, Permissions},
+    io::Write,
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use brig_common::api::{api::ErrorCode, config::ConfigVersion};
+use chrono::Utc;
+
+const VERSION_FORMAT: &str = "%Y%m%d%H%M%S%6f";
+
+/// `path` with `suffix` appended to its file name, for the files brig keeps
+/// next to the config
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Directory next to the config file holding its previous versions
+fn backup_dir(path: &Path) -> PathBuf {
+    with_suffix(path, ".backups")
+}
+
+/// Where the config saved as `version` is kept. Versions are timestamps, so
+/// anything else is rejected rather than joined onto the path.
+pub fn version_path(path: &Path, version: &str) -> Result<PathBuf, ErrorCode> {
+    if version.is_empty() || !version.chars().all(|c| c.is_ascii_digit()) {
+        return Err(ErrorCode::ConfigVersionNotFound {
+            version: version.to_owned(),
+        });
+    }
+    let version_path = backup_dir(path).join(format!("{}.json", version));
+    if !version_path.is_file() {
+        return Err(ErrorCode::ConfigVersionNotFound {
+            version: version.to_owned(),
+        });
+    }
+    Ok(version_path)
+}
+
+/// Previous versions of the config, newest first
+pub fn list_versions(path: &Path) -> Result<Vec<ConfigVersion>, ErrorCode> {
+    let dir = backup_dir(path);
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let err = || ErrorCode::ErrorReadingConfigBackups { path: dir.clone() };
+    let mut versions = vec![];
+    for entry in fs::read_dir(&dir).map_err(|_| err())? {
+        let entry = entry.map_err(|_| err())?;
+        let file_name = entry.file_name();
+        let Some(version) = file_name
+            .to_str()
+            .and_then(|name| name.strip_suffix(".json"))
+        else {
+            continue;
+        };
+        let size = entry.metadata().map_err(|_| err())?.len();
+        versions.push(ConfigVersion {
+            version: version.to_owned(),
+            size,
+        });
+    }
+    versions.sort_by(|a, b| b.version.cmp(&a.version));
+    Ok(versions)
+}
+
+/// Copies the config currently at `path` into the backups, then drops the
+/// oldest backups beyond `keep`. The backups keep the config's permissions
+/// and their directory is only accessible to brig's user, as the config
+/// names key files and hook commands.
+pub fn back_up(path: &Path, keep: usize) -> Result<(), ErrorCode> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let dir = backup_dir(path);
+    let err = || ErrorCode::ErrorWritingConfigFile { path: dir.clone() };
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)
+        .map_err(|_| err())?;
+    // directories made by older versions weren't restricted
+    fs::set_permissions(&dir, Permissions::from_mode(0o700)).map_err(|_| err())?;
+    let version = Utc::now().format(VERSION_FORMAT).to_string();
+    fs::copy(path, dir.join(format!("{}.json", version))).map_err(|_| err())?;
+
+    for old in list_versions(path)?.iter().skip(keep) {
+        fs::remove_file(dir.join(format!("{}.json", &old.version))).map_err(|_| err())?;
+    }
+    Ok(())
+}
+
+/// Replaces the file at `path` with `contents` so that a crash leaves either
+/// the old or the new file, never a partial one: the contents go to a temp
+/// file that is synced to disk and then renamed over `path`. The new file
+/// keeps the permissions of the one it replaces, and is only readable by
+/// brig's user if there was none.
+pub fn write_atomic(path: &Path, contents: &str) -> Result<(), ErrorCode> {
+    let err = || ErrorCode::ErrorWritingConfigFile {
+        path: path.to_path_buf(),
+    };
+    let permissions = match fs::metadata(path) {
+        Ok(metadata) => metadata.permissions(),
+        Err(_) => Permissions::from_mode(0o600),
+    };
+    let tmp_path = with_suffix(path, ".tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .map_err(|_| err())?;
+    // a temp file left behind by a crash keeps the mode it was created with
+    file.set_permissions(permissions).map_err(|_| err())?;
+    file.write_all(contents.as_bytes()).map_err(|_| err())?;
+    file.sync_all().map_err(|_| err())?;
+    drop(file);
+    fs::rename(&tmp_path, path).map_err(|_| err())?;
+
+    // make the rename itself durable
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    File::open(parent)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|_| err())
+}
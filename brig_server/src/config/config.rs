@@ -1,9 +1,175 @@
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use brig_common::api::api::ErrorCode;
 use serde::{Deserialize, Serialize};
 
-use super::{dataset::Dataset, server::Server};
+use super::{
+    backup,
+    dataset::Dataset,
+    mount::CanMount,
+    naming::{self, SnapshotNaming},
+    probe::Probe,
+    server::{Backend, Privilege, Server},
+    ssh::{KnownHostsMode, SshPool},
+    timeouts::Timeouts,
+};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
     pub servers: Vec<Server>,
     pub datasets: Vec<Dataset>,
+    #[serde(default)]
+    pub snapshot_naming: SnapshotNaming,
+    /// How many previous versions of this file to keep when it's rewritten
+    #[serde(default = "default_config_backups")]
+    pub config_backups: usize,
+    #[serde(default)]
+    pub ssh_pool: SshPool,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
+    pub probe: Probe,
+}
+
+fn default_config_backups() -> usize {
+    10
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let config = std::fs::read_to_string(path)
+            .with_context(|| format!("unable to read config file {}", path.display()))?;
+        let config: Config = serde_json::from_str(&config)
+            .with_context(|| format!("invalid config file {}", path.display()))?;
+        config
+            .validate()
+            .with_context(|| format!("invalid config file {}", path.display()))?;
+        Ok(config)
+    }
+
+    /// Writes the config to `path` atomically, keeping the file it replaces
+    /// as a backup
+    pub fn save(&self, path: &Path) -> Result<(), ErrorCode> {
+        let json_str =
+            serde_json::to_string_pretty(self).map_err(|_| ErrorCode::ConfigIsInvalidJson)?;
+        backup::back_up(path, self.config_backups)?;
+        backup::write_atomic(path, &json_str)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !naming::is_valid_name_segment(&self.snapshot_naming.prefix) {
+            bail!(
+                "snapshot prefix `{}` may only contain letters, digits, `-`, `_`, `.` and `:`",
+                self.snapshot_naming.prefix
+            );
+        }
+        if self.ssh_pool.max_sessions_per_host == 0 {
+            bail!("ssh_pool.max_sessions_per_host must be at least 1");
+        }
+        let timeouts = &self.timeouts;
+        if timeouts.connect == 0
+            || timeouts.command == 0
+            || timeouts.long_command == 0
+            || timeouts.keepalive == 0
+            || timeouts.transfer_inactivity == 0
+            || timeouts.hook == 0
+        {
+            bail!("timeouts must be greater than zero");
+        }
+        if self.probe.interval == 0 {
+            bail!("probe.interval must be greater than zero");
+        }
+        for server in &self.servers {
+            if let Some(threshold) = server
+                .capacity_threshold
+                .filter(|threshold| !(1..=100).contains(threshold))
+            {
+                bail!(
+                    "server {} has capacity_threshold {}, expected a percentage between 1 and 100",
+                    server.name,
+                    threshold
+                );
+            }
+            if server.backend == Backend::Agent {
+                if server.agent.is_none() {
+                    bail!(
+                        "server {} uses the agent backend but has no agent",
+                        server.name
+                    );
+                }
+                if server
+                    .privilege
+                    .as_ref()
+                    .is_some_and(|privilege| *privilege != Privilege::None)
+                {
+                    bail!(
+                        "server {} uses the agent backend, which runs zfs with the agent's own rights, so it can't have a privilege",
+                        server.name
+                    );
+                }
+            }
+            if server.privilege == Some(Privilege::Wrapper(vec![])) {
+                bail!("server {} has an empty privilege wrapper", server.name);
+            }
+            if server.ssh.connect_timeout == Some(0) {
+                bail!(
+                    "server {} needs a connect_timeout greater than zero",
+                    server.name
+                );
+            }
+            if let Some(fingerprint) = &server.ssh.host_key_fingerprint {
+                if !fingerprint.starts_with("SHA256:") {
+                    bail!(
+                        "server {} has host_key_fingerprint `{}`, expected `SHA256:...` as printed by ssh-keygen -l",
+                        server.name,
+                        fingerprint
+                    );
+                }
+                if server.ssh.known_hosts != KnownHostsMode::Strict {
+                    bail!(
+                        "server {} sets both host_key_fingerprint and known_hosts, only one of them is used",
+                        server.name
+                    );
+                }
+                if !server.ssh.jump_hosts.is_empty() {
+                    bail!(
+                        "server {} pins host_key_fingerprint but connects through jump_hosts, whose keys can't be pinned",
+                        server.name
+                    );
+                }
+            }
+        }
+        for dataset in &self.datasets {
+            if dataset
+                .failover
+                .as_ref()
+                .is_some_and(|policy| policy.probe_interval == 0 || policy.max_failed_probes == 0)
+            {
+                bail!(
+                    "dataset {} needs a probe_interval and max_failed_probes greater than zero",
+                    dataset.name
+                );
+            }
+            if dataset
+                .mount
+                .is_some_and(|policy| policy.owner_canmount == CanMount::Off)
+            {
+                bail!(
+                    "dataset {} has owner_canmount off, but the owner must be mounted",
+                    dataset.name
+                );
+            }
+            for server in dataset.server_lifetimes.keys() {
+                if !self.servers.iter().any(|s| &s.name == server) {
+                    bail!(
+                        "dataset {} has a snapshot lifetime for unknown server {}",
+                        dataset.name,
+                        server
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
 }
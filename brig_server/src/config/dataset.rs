@@ -1,9 +1,49 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
+use super::{
+    failover::FailoverPolicy, hooks::SwitchHooks, lifetime::Lifetime, mount::MountPolicy,
+    server::Server,
+};
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Dataset {
     pub name: String,
     pub owner: String,
     pub server: String,
-    pub snapshot_lifetime: String,
+    pub snapshot_lifetime: Lifetime,
+    /// Per-server overrides of `snapshot_lifetime`, keyed by server name
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub server_lifetimes: HashMap<String, Lifetime>,
+    /// Snapshots (the part after the `@`) that `clean` must never destroy
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pinned_snapshots: Vec<String>,
+    /// Promote a replica automatically if the owner stops responding
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failover: Option<FailoverPolicy>,
+    /// Old owners replaced by a failover. They may hold snapshots the new
+    /// owner never got, so they aren't synced to until the failover is
+    /// acknowledged with `POST /failover/acknowledge`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fenced_servers: Vec<String>,
+    #[serde(default, skip_serializing_if = "SwitchHooks::is_empty")]
+    pub hooks: SwitchHooks,
+    /// Mount the dataset only on its owner, enforced after every receive and
+    /// during switches
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mount: Option<MountPolicy>,
+}
+
+impl Dataset {
+    /// How long snapshots of this dataset are kept on `server`.
+    /// A (dataset, server) override wins over the server's default, which wins
+    /// over the dataset's `snapshot_lifetime`.
+    pub fn lifetime_on(&self, server: &Server) -> Lifetime {
+        self.server_lifetimes
+            .get(&server.name)
+            .copied()
+            .or(server.snapshot_lifetime)
+            .unwrap_or(self.snapshot_lifetime)
+    }
 }
@@ -0,0 +1,20 @@
+use serde::{Deserialize, Serialize};
+
+/// When to give up on a dataset's owner and promote a replica instead
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FailoverPolicy {
+    /// seconds between health checks of the owner
+    #[serde(default = "default_probe_interval")]
+    pub probe_interval: u64,
+    /// consecutive failed health checks before a replica is promoted
+    #[serde(default = "default_max_failed_probes")]
+    pub max_failed_probes: u32,
+}
+
+fn default_probe_interval() -> u64 {
+    30
+}
+
+fn default_max_failed_probes() -> u32 {
+    3
+}
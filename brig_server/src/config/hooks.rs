@@ -0,0 +1,22 @@
+use serde::{Deserialize, Serialize};
+
+/// Shell commands run around a switch, e.g. to stop services or change
+/// exports. Demote hooks run on the old owner, promote hooks on the new one.
+/// A failing hook aborts the switch and rolls it back.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct SwitchHooks {
+    pub pre_demote: Vec<String>,
+    pub post_demote: Vec<String>,
+    pub pre_promote: Vec<String>,
+    pub post_promote: Vec<String>,
+}
+
+impl SwitchHooks {
+    pub fn is_empty(&self) -> bool {
+        self.pre_demote.is_empty()
+            && self.post_demote.is_empty()
+            && self.pre_promote.is_empty()
+            && self.post_promote.is_empty()
+    }
+}
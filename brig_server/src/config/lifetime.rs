@@ -0,0 +1,230 @@
+use std::{fmt, str::FromStr};
+
+use chrono::{DateTime, Duration, Months, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+/// How long a snapshot is kept before `clean` destroys it.
+///
+/// Written as one or more `<number><unit>` pairs, where the unit is one of
+/// `y` (years), `M` (months), `w` (weeks), `d` (days) or `h` (hours),
+/// e.g. `12h`, `3d`, `1y6M`. `never` keeps snapshots forever.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(try_from = "String", into = "String")]
+pub enum Lifetime {
+    Never,
+    Span {
+        years: u32,
+        months: u32,
+        weeks: u32,
+        days: u32,
+        hours: u32,
+    },
+}
+
+impl Lifetime {
+    /// Snapshots created before the returned time have expired.
+    /// Returns `None` if snapshots never expire.
+    pub fn cutoff<Tz: TimeZone>(&self, now: DateTime<Tz>) -> Option<DateTime<Tz>> {
+        match *self {
+            Lifetime::Never => None,
+            Lifetime::Span {
+                years,
+                months,
+                weeks,
+                days,
+                hours,
+            } => now
+                .checked_sub_months(Months::new(years.checked_mul(12)?.checked_add(months)?))?
+                .checked_sub_signed(Duration::weeks(weeks.into()))?
+                .checked_sub_signed(Duration::days(days.into()))?
+                .checked_sub_signed(Duration::hours(hours.into())),
+        }
+    }
+}
+
+impl FromStr for Lifetime {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: String| format!("invalid lifetime `{}`: {}", s, reason);
+
+        let trimmed = s.trim();
+        if trimmed == "never" {
+            return Ok(Lifetime::Never);
+        }
+        if trimmed.is_empty() {
+            return Err(invalid(
+                "expected e.g. `12h`, `3d`, `2w`, `6M`, `1y` or `never`".into(),
+            ));
+        }
+
+        let (mut years, mut months, mut weeks, mut days, mut hours) =
+            (None, None, None, None, None);
+        let mut digits = String::new();
+        for c in trimmed.chars() {
+            if c.is_ascii_digit() {
+                digits.push(c);
+                continue;
+            }
+            if digits.is_empty() {
+                return Err(invalid(format!("expected a number before `{}`", c)));
+            }
+            let value: u32 = digits
+                .parse()
+                .map_err(|_| invalid(format!("{} is too large", digits)))?;
+            digits.clear();
+
+            let unit = match c {
+                'y' => &mut years,
+                'M' => &mut months,
+                'w' => &mut weeks,
+                'd' => &mut days,
+                'h' => &mut hours,
+                _ => {
+                    return Err(invalid(format!(
+                        "unknown unit `{}`, expected one of y, M, w, d, h",
+                        c
+                    )));
+                }
+            };
+            if unit.is_some() {
+                return Err(invalid(format!("unit `{}` is given more than once", c)));
+            }
+            *unit = Some(value);
+        }
+        if !digits.is_empty() {
+            return Err(invalid(format!("missing unit after {}", digits)));
+        }
+
+        if [years, months, weeks, days, hours]
+            .iter()
+            .all(|value| value.unwrap_or(0) == 0)
+        {
+            return Err(invalid(
+                "must be greater than zero, use `never` to keep snapshots forever".into(),
+            ));
+        }
+        let lifetime = Lifetime::Span {
+            years: years.unwrap_or(0),
+            months: months.unwrap_or(0),
+            weeks: weeks.unwrap_or(0),
+            days: days.unwrap_or(0),
+            hours: hours.unwrap_or(0),
+        };
+        // a span too long to subtract from now would have no cutoff, which
+        // callers take to mean `never`
+        if lifetime.cutoff(Utc::now()).is_none() {
+            return Err(invalid(
+                "is too long, use `never` to keep snapshots forever".into(),
+            ));
+        }
+        Ok(lifetime)
+    }
+}
+
+impl TryFrom<String> for Lifetime {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for Lifetime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Lifetime::Never => write!(f, "never"),
+            Lifetime::Span {
+                years,
+                months,
+                weeks,
+                days,
+                hours,
+            } => {
+                for (value, unit) in [
+                    (years, 'y'),
+                    (months, 'M'),
+                    (weeks, 'w'),
+                    (days, 'd'),
+                    (hours, 'h'),
+                ] {
+                    if value > 0 {
+                        write!(f, "{}{}", value, unit)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl From<Lifetime> for String {
+    fn from(value: Lifetime) -> Self {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(years: u32, months: u32, weeks: u32, days: u32, hours: u32) -> Lifetime {
+        Lifetime::Span {
+            years,
+            months,
+            weeks,
+            days,
+            hours,
+        }
+    }
+
+    #[test]
+    fn parses_single_and_compound_spans() {
+        assert_eq!("12h".parse(), Ok(span(0, 0, 0, 0, 12)));
+        assert_eq!("3d".parse(), Ok(span(0, 0, 0, 3, 0)));
+        assert_eq!(" 1y6M ".parse(), Ok(span(1, 6, 0, 0, 0)));
+        assert_eq!("2w1d12h".parse(), Ok(span(0, 0, 2, 1, 12)));
+        assert_eq!("never".parse(), Ok(Lifetime::Never));
+    }
+
+    #[test]
+    fn round_trips_through_display() {
+        for text in ["12h", "1y6M", "2w1d12h", "never"] {
+            assert_eq!(text.parse::<Lifetime>().unwrap().to_string(), text);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_spans() {
+        for text in ["", "d", "3", "3x", "1d2d", "3D", "-1d", "1.5d"] {
+            assert!(text.parse::<Lifetime>().is_err(), "{} was accepted", text);
+        }
+    }
+
+    #[test]
+    fn rejects_zero() {
+        assert!("0d".parse::<Lifetime>().is_err());
+        assert!("0y0h".parse::<Lifetime>().is_err());
+    }
+
+    #[test]
+    fn rejects_overflow() {
+        assert!("99999999999d".parse::<Lifetime>().is_err());
+        assert!("4000000000y".parse::<Lifetime>().is_err());
+        assert!("300000y".parse::<Lifetime>().is_err());
+    }
+
+    #[test]
+    fn cutoff_subtracts_every_unit() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 31, 12, 0, 0).unwrap();
+        assert_eq!(
+            span(0, 1, 0, 0, 0).cutoff(now),
+            Some(Utc.with_ymd_and_hms(2024, 2, 29, 12, 0, 0).unwrap())
+        );
+        assert_eq!(
+            span(1, 0, 1, 1, 1).cutoff(now),
+            Some(Utc.with_ymd_and_hms(2023, 3, 23, 11, 0, 0).unwrap())
+        );
+        assert_eq!(Lifetime::Never.cutoff(now), None);
+    }
+}
@@ -1,3 +1,14 @@
+pub mod agent;
+pub mod backup;
+#[allow(clippy::module_inception)]
 pub mod config;
 pub mod dataset;
+pub mod failover;
+pub mod hooks;
+pub mod lifetime;
+pub mod mount;
+pub mod naming;
+pub mod probe;
 pub mod server;
+pub mod ssh;
+pub mod timeouts;
@@ -0,0 +1,56 @@
+use serde::{Deserialize, Serialize};
+
+/// Values of the zfs `canmount` property
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum CanMount {
+    On,
+    Off,
+    Noauto,
+}
+
+impl CanMount {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CanMount::On => "on",
+            CanMount::Off => "off",
+            CanMount::Noauto => "noauto",
+        }
+    }
+}
+
+/// How a dataset is mounted on its owner and on its replicas. The owner is
+/// mounted at its `mountpoint`, replicas are unmounted so nothing reads stale
+/// data from them.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct MountPolicy {
+    /// `canmount` on the owner, either `on` or `noauto`
+    pub owner_canmount: CanMount,
+    pub replica_canmount: CanMount,
+}
+
+impl Default for MountPolicy {
+    fn default() -> Self {
+        Self {
+            owner_canmount: CanMount::On,
+            replica_canmount: CanMount::Noauto,
+        }
+    }
+}
+
+/// Whether a server owns a dataset or holds a replica of it
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MountRole {
+    Owner,
+    Replica,
+}
+
+impl MountPolicy {
+    pub fn canmount(&self, role: MountRole) -> CanMount {
+        match role {
+            MountRole::Owner => self.owner_canmount,
+            MountRole::Replica => self.replica_canmount,
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::sync::OnceLock;
+
+use chrono::{DateTime, Local, NaiveDateTime, Offset, TimeDelta, TimeZone, Utc};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+const TIMESTAMP_FORMAT: &str = "%Y%m%d%H%M%S";
+
+/// How brig names the snapshots it takes:
+/// `<prefix>-<YYYYmmddHHMMSS>[Z][.<n>][-<label>]`
+///
+/// `Z` marks a UTC timestamp, `.<n>` disambiguates snapshots taken within the
+/// same second and the label records why the snapshot was taken.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct SnapshotNaming {
+    pub prefix: String,
+    /// Use UTC instead of brig's local time for timestamps
+    pub utc: bool,
+    /// Append what triggered a snapshot (e.g. `scheduled`, `manual`) to its name
+    pub labels: bool,
+    #[serde(skip)]
+    pattern: OnceLock<Regex>,
+}
+
+impl Default for SnapshotNaming {
+    fn default() -> Self {
+        Self {
+            prefix: "brig".to_owned(),
+            utc: false,
+            labels: false,
+            pattern: OnceLock::new(),
+        }
+    }
+}
+
+/// What caused brig to take a snapshot
+#[derive(Clone, Copy)]
+pub enum SnapshotOrigin {
+    Scheduled,
+    Manual,
+    PreSwitch,
+}
+
+impl SnapshotOrigin {
+    fn label(&self) -> &'static str {
+        match self {
+            SnapshotOrigin::Scheduled => "scheduled",
+            SnapshotOrigin::Manual => "manual",
+            SnapshotOrigin::PreSwitch => "pre-switch",
+        }
+    }
+}
+
+/// A snapshot name that matched the naming template
+pub struct ParsedSnapshot {
+    pub created: DateTime<Utc>,
+}
+
+/// Characters zfs accepts in a snapshot name, minus the space
+pub fn is_valid_name_segment(segment: &str) -> bool {
+    !segment.is_empty()
+        && segment
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+impl SnapshotNaming {
+    /// The label to put in the name of a snapshot taken because of `origin`
+    pub fn origin_label(&self, origin: SnapshotOrigin) -> Option<&'static str> {
+        self.labels.then(|| origin.label())
+    }
+
+    /// The part after the `@` for a snapshot taken at `now`. `sequence` is
+    /// bumped by the caller when the name is already taken.
+    pub fn name(&self, now: DateTime<Utc>, label: Option<&str>, sequence: u32) -> String {
+        let mut name = if self.utc {
+            format!("{}-{}Z", self.prefix, now.format(TIMESTAMP_FORMAT))
+        } else {
+            format!(
+                "{}-{}",
+                self.prefix,
+                now.with_timezone(&Local).format(TIMESTAMP_FORMAT)
+            )
+        };
+        if sequence > 0 {
+            name.push_str(&format!(".{}", sequence));
+        }
+        if let Some(label) = label {
+            name.push_str(&format!("-{}", label));
+        }
+        name
+    }
+
+    /// Parses the part after the `@` of a snapshot taken by brig. Returns
+    /// `None` for snapshots that don't follow the template. Timestamps
+    /// without a `Z` are read in local time, regardless of `utc`, so changing
+    /// the setting doesn't orphan existing snapshots.
+    pub fn parse(&self, name: &str) -> Option<ParsedSnapshot> {
+        let pattern = self.pattern.get_or_init(|| {
+            Regex::new(&format!(
+                r"^{}-(\d{{14}})(Z?)(?:\.\d+)?(?:-.+)?$",
+                regex::escape(&self.prefix)
+            ))
+            .unwrap()
+        });
+        let caps = pattern.captures(name)?;
+        let timestamp = NaiveDateTime::parse_from_str(&caps[1], TIMESTAMP_FORMAT).ok()?;
+        let created = if &caps[2] == "Z" {
+            timestamp.and_utc()
+        } else {
+            match Local.from_local_datetime(&timestamp).earliest() {
+                Some(created) => created.with_timezone(&Utc),
+                // a time skipped when the clocks went forward doesn't exist in
+                // local time, the offset around it is used instead so the
+                // snapshot is still cleaned up
+                None => {
+                    let offset = Local.offset_from_utc_datetime(&timestamp).fix();
+                    (timestamp - TimeDelta::seconds(offset.local_minus_utc().into())).and_utc()
+                }
+            }
+        };
+        Some(ParsedSnapshot { created })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn naming(utc: bool) -> SnapshotNaming {
+        SnapshotNaming {
+            utc,
+            ..SnapshotNaming::default()
+        }
+    }
+
+    #[test]
+    fn formats_utc_names() {
+        let now = Utc.with_ymd_and_hms(2024, 5, 1, 13, 4, 5).unwrap();
+        let naming = naming(true);
+        assert_eq!(naming.name(now, None, 0), "brig-20240501130405Z");
+        assert_eq!(
+            naming.name(now, Some("manual"), 2),
+            "brig-20240501130405Z.2-manual"
+        );
+    }
+
+    #[test]
+    fn parses_what_it_names() {
+        let now = Utc.with_ymd_and_hms(2024, 5, 1, 13, 4, 5).unwrap();
+        for utc in [true, false] {
+            let naming = naming(utc);
+            for (label, sequence) in [
+                (None, 0),
+                (None, 3),
+                (Some("pre-switch"), 0),
+                (Some("manual"), 12),
+            ] {
+                let name = naming.name(now, label, sequence);
+                let parsed = naming.parse(&name);
+                assert_eq!(parsed.map(|parsed| parsed.created), Some(now), "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn reads_local_names_after_switching_to_utc() {
+        let now = Utc.with_ymd_and_hms(2024, 5, 1, 13, 4, 5).unwrap();
+        let name = naming(false).name(now, Some("scheduled"), 1);
+        assert_eq!(
+            naming(true).parse(&name).map(|parsed| parsed.created),
+            Some(now)
+        );
+    }
+
+    #[test]
+    fn parses_local_times_skipped_by_dst() {
+        // 02:30 doesn't exist in most European zones on this date
+        assert!(naming(false).parse("brig-20240331023000").is_some());
+        assert!(naming(false).parse("brig-20240310023000").is_some());
+    }
+
+    #[test]
+    fn ignores_other_snapshots() {
+        let naming = SnapshotNaming {
+            prefix: "a.b".to_owned(),
+            ..SnapshotNaming::default()
+        };
+        for name in [
+            "manual",
+            "brig-20240501130405",
+            "axb-20240501130405",
+            "a.b-2024050113040",
+            "a.b-20241301130405",
+            "a.b-20240501130405Z.",
+        ] {
+            assert!(naming.parse(name).is_none(), "{} was parsed", name);
+        }
+        assert!(naming.parse("a.b-20240501130405Z").is_some());
+    }
+}
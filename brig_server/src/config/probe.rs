@@ -0,0 +1,15 @@
+use serde::{Deserialize, Serialize};
+
+/// How often every server is probed in the background, see `/servers`
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Probe {
+    /// seconds between probes of each server
+    pub interval: u64,
+}
+
+impl Default for Probe {
+    fn default() -> Self {
+        Self { interval: 60 }
+    }
+}
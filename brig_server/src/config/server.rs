@@ -1,9 +1,104 @@
 use serde::{Deserialize, Serialize};
 
+use super::{agent::AgentOptions, lifetime::Lifetime, ssh::SshOptions};
+
+/// How brig runs commands on a server
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// over SSH as `user@address`
+    #[default]
+    Ssh,
+    /// as local processes, for the host brig runs on
+    Local,
+    /// through the brig_agent running on the server, see `Server.agent`
+    Agent,
+}
+
+impl Backend {
+    fn is_default(&self) -> bool {
+        *self == Backend::default()
+    }
+}
+
+/// How brig gains the rights zfs needs on a server
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Privilege {
+    /// run zfs as `user`, relying on root or `zfs allow` delegation, the
+    /// default
+    None,
+    /// `sudo -n zfs ...`
+    Sudo,
+    /// `doas -n zfs ...`
+    Doas,
+    /// a command and arguments that zfs is appended to, e.g. `["pfexec"]`
+    Wrapper(Vec<String>),
+}
+
+impl Privilege {
+    /// The program and arguments that run `program` with this privilege
+    pub fn wrap(&self, program: &str) -> (String, Vec<String>) {
+        match self {
+            Privilege::None => (program.to_owned(), vec![]),
+            Privilege::Sudo => ("sudo".to_owned(), vec!["-n".to_owned(), program.to_owned()]),
+            Privilege::Doas => ("doas".to_owned(), vec!["-n".to_owned(), program.to_owned()]),
+            Privilege::Wrapper(wrapper) => {
+                let mut args = wrapper[1..].to_vec();
+                args.push(program.to_owned());
+                (wrapper[0].clone(), args)
+            }
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Privilege::None => "none",
+            Privilege::Sudo => "sudo",
+            Privilege::Doas => "doas",
+            Privilege::Wrapper(_) => "wrapper",
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Server {
     pub name: String,
     pub user: String,
     pub address: String,
     pub pool: String,
+    /// Overrides `Dataset.snapshot_lifetime` for every dataset on this server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot_lifetime: Option<Lifetime>,
+    /// Pool capacity in percent above which the oldest brig snapshots are pruned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capacity_threshold: Option<u8>,
+    #[serde(default, skip_serializing_if = "Backend::is_default")]
+    pub backend: Backend,
+    /// Applied to every zfs, zpool and fuser command brig runs, but not to
+    /// hooks. See `Server::privilege` for the default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub privilege: Option<Privilege>,
+    /// Required with the agent backend
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent: Option<AgentOptions>,
+    /// Only used with the ssh backend
+    #[serde(default, skip_serializing_if = "SshOptions::is_default")]
+    pub ssh: SshOptions,
+}
+
+impl Server {
+    /// The configured privilege, `none` if there isn't one. The agent runs
+    /// zfs with its own rights.
+    ///
+    /// Before `privilege` could be configured, brig ran `zfs set readonly`
+    /// through `sudo` and every other command directly. Servers that had a
+    /// sudo rule for that instead of `zfs allow readonly` need
+    /// `"privilege": "sudo"`, or the `readonly` permission delegated.
+    pub fn privilege(&self) -> Privilege {
+        match (&self.backend, &self.privilege) {
+            (Backend::Agent, _) | (_, None) => Privilege::None,
+            (_, Some(privilege)) => privilege.clone(),
+        }
+    }
 }
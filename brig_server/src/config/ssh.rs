@@ -0,0 +1,69 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+/// Limits for the SSH sessions brig keeps open to each server, read at startup
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct SshPool {
+    /// sessions kept per server, each can run many commands at once
+    pub max_sessions_per_host: usize,
+    /// seconds an unused session stays open
+    pub idle_timeout: u64,
+}
+
+impl Default for SshPool {
+    fn default() -> Self {
+        Self {
+            max_sessions_per_host: 4,
+            idle_timeout: 300,
+        }
+    }
+}
+
+/// How the host key of a server is checked against `~/.ssh/known_hosts`
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum KnownHostsMode {
+    /// the key must already be known
+    #[default]
+    Strict,
+    /// unknown keys are added, changed keys are refused
+    AcceptNew,
+    /// any key is accepted
+    Accept,
+}
+
+/// How to reach a server over SSH, so isolated hosts don't need an entry in
+/// `~/.ssh/config`
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Default, Debug)]
+#[serde(default)]
+pub struct SshOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identity_file: Option<PathBuf>,
+    #[serde(skip_serializing_if = "is_default_mode")]
+    pub known_hosts: KnownHostsMode,
+    /// `SHA256:...` as printed by `ssh-keygen -l`. The key is then checked
+    /// against this instead of `known_hosts`, before authenticating. Can't
+    /// be combined with `jump_hosts`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host_key_fingerprint: Option<String>,
+    /// seconds to wait for the connection
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_timeout: Option<u64>,
+    /// `[user@]host[:port]` of the hosts to jump through, in order
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub jump_hosts: Vec<String>,
+}
+
+fn is_default_mode(mode: &KnownHostsMode) -> bool {
+    *mode == KnownHostsMode::default()
+}
+
+impl SshOptions {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
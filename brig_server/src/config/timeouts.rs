@@ -0,0 +1,36 @@
+use serde::{Deserialize, Serialize};
+
+/// How long brig waits on a server before giving up, in seconds. A config
+/// restore applies to connections made after it.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Timeouts {
+    /// for an SSH connection, unless the server sets `ssh.connect_timeout`
+    pub connect: u64,
+    /// for a short command such as `zfs get`, `zfs set` or `zfs snapshot`
+    pub command: u64,
+    /// for `zfs destroy`, `zfs diff` and listing snapshots, which can take
+    /// much longer on a big pool
+    pub long_command: u64,
+    /// between SSH keepalives. ssh gives up on a connection that missed
+    /// three of them, failing whatever was running over it.
+    pub keepalive: u64,
+    /// for a transfer that isn't moving any bytes
+    pub transfer_inactivity: u64,
+    /// for a switch hook. A hook that runs longer fails, which rolls the
+    /// switch back.
+    pub hook: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: 30,
+            command: 120,
+            long_command: 1800,
+            keepalive: 15,
+            transfer_inactivity: 300,
+            hook: 300,
+        }
+    }
+}
@@ -0,0 +1,278 @@
+use std::{io, time::Duration};
+
+use async_trait::async_trait;
+use brig_common::{
+    agent::{self, AgentResponse, Challenge, Operation, Stream},
+    api::api::ErrorCode,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+    net::{TcpStream, tcp::OwnedWriteHalf},
+    sync::oneshot,
+};
+
+use super::{ByteReader, ByteWriter, CommandOutput, Executor, RunningCommand};
+use crate::config::{
+    server::{Privilege, Server},
+    timeouts::Timeouts,
+};
+
+/// Runs commands through the brig_agent on a server, one connection per
+/// command
+pub struct AgentExecutor {
+    server: String,
+    address: String,
+    token: Vec<u8>,
+    privilege: Privilege,
+    timeouts: Timeouts,
+}
+
+impl AgentExecutor {
+    /// Reads the token and checks that the agent accepts connections
+    pub async fn connect(server: &Server, timeouts: Timeouts) -> Result<Self, ErrorCode> {
+        let fail = |msg: String| ErrorCode::AgentConnectionFail {
+            server: server.name.clone(),
+            msg,
+        };
+        let options = server
+            .agent
+            .as_ref()
+            .ok_or_else(|| fail("no agent configured".to_owned()))?;
+        let token = tokio::fs::read_to_string(&options.token_file)
+            .await
+            .map_err(|e| fail(format!("{}: {}", options.token_file.display(), e)))?;
+        let executor = Self {
+            server: server.name.clone(),
+            address: format!("{}:{}", &server.address, options.port),
+            token: agent::parse_token(&token).map_err(|e| fail(e.to_string()))?,
+            privilege: server.privilege(),
+            timeouts,
+        };
+
+        let seconds = executor.timeouts.connect;
+        tokio::time::timeout(Duration::from_secs(seconds), executor.open())
+            .await
+            .map_err(|_| ErrorCode::ConnectTimeout {
+                server: server.name.clone(),
+                seconds,
+            })?
+            .map_err(|e| fail(e.to_string()))?;
+        Ok(executor)
+    }
+
+    /// A connection that has passed the agent's challenge
+    async fn open(&self) -> io::Result<(TcpStream, String)> {
+        let mut conn = TcpStream::connect(&self.address).await?;
+        let challenge: Challenge = agent::read_message(&mut conn).await?;
+        Ok((conn, challenge.nonce))
+    }
+
+    /// Sends `operation` and returns the connection with the agent's first
+    /// reply
+    async fn request(&self, operation: &Operation) -> io::Result<(TcpStream, AgentResponse)> {
+        let (mut conn, nonce) = self.open().await?;
+        let signed = agent::sign(&self.token, &nonce, operation)?;
+        agent::write_message(&mut conn, &signed).await?;
+        match agent::read_message(&mut conn).await? {
+            AgentResponse::Refused(e) => Err(io::Error::other(format!(
+                "agent on {} refused: {:?}",
+                &self.server, e
+            ))),
+            response => Ok((conn, response)),
+        }
+    }
+}
+
+fn exited(response: AgentResponse) -> io::Result<CommandOutput> {
+    match response {
+        AgentResponse::Exited {
+            status,
+            stdout,
+            stderr,
+        } => Ok(CommandOutput {
+            status,
+            stdout,
+            stderr,
+        }),
+        response => Err(io::Error::other(format!(
+            "unexpected reply from agent: {:?}",
+            response
+        ))),
+    }
+}
+
+/// A command running on the agent. A task moves its stream between the
+/// connection and an in-memory pipe, then reads how it exited.
+struct AgentCommand {
+    stdin: Option<ByteWriter>,
+    stdout: Option<ByteReader>,
+    exited: oneshot::Receiver<io::Result<CommandOutput>>,
+}
+
+/// Fails with `io::ErrorKind::TimedOut` if `io` makes no progress within
+/// `inactivity`, so a stuck agent doesn't leak the task
+async fn within<T>(inactivity: Duration, io: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    tokio::time::timeout(inactivity, io)
+        .await
+        .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+}
+
+/// Copies data frames from the agent into `pipe` until the empty frame
+async fn receive_stream(
+    conn: &mut TcpStream,
+    mut pipe: DuplexStream,
+    inactivity: Duration,
+) -> io::Result<()> {
+    loop {
+        let data = within(inactivity, agent::read_frame(conn)).await?;
+        if data.is_empty() {
+            return Ok(());
+        }
+        within(inactivity, pipe.write_all(&data)).await?;
+    }
+}
+
+/// Copies `pipe` to the agent as data frames until it's shut down
+async fn send_stream(
+    mut conn: OwnedWriteHalf,
+    mut pipe: DuplexStream,
+    inactivity: Duration,
+) -> io::Result<()> {
+    let mut buffer = [0u8; 65536];
+    loop {
+        let n = within(inactivity, pipe.read(&mut buffer)).await?;
+        within(inactivity, agent::write_frame(&mut conn, &buffer[..n])).await?;
+        if n == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// Sends `pipe` to the agent while listening for how the command exited, so
+/// a command that stops reading its stdin, most likely because it failed,
+/// is reported as soon as the agent knows instead of once the stream stalls
+async fn send_stream_and_wait(
+    conn: TcpStream,
+    pipe: DuplexStream,
+    inactivity: Duration,
+) -> io::Result<CommandOutput> {
+    let (mut reader, writer) = conn.into_split();
+    let mut sending = tokio::spawn(send_stream(writer, pipe, inactivity));
+    // polled to completion rather than dropped, a partly read reply would
+    // otherwise be lost
+    let response = agent::read_message(&mut reader);
+    tokio::pin!(response);
+    tokio::select! {
+        streamed = &mut sending => {
+            let streamed = streamed.map_err(io::Error::other)?;
+            within(inactivity, response)
+                .await
+                .and_then(exited)
+                .or_else(|e| streamed.and(Err(e)))
+        }
+        response = &mut response => {
+            sending.abort();
+            response.and_then(exited)
+        }
+    }
+}
+
+#[async_trait]
+impl RunningCommand for AgentCommand {
+    fn take_stdin(&mut self) -> Option<ByteWriter> {
+        self.stdin.take()
+    }
+
+    fn take_stdout(&mut self) -> Option<ByteReader> {
+        self.stdout.take()
+    }
+
+    async fn wait(self: Box<Self>) -> io::Result<CommandOutput> {
+        self.exited
+            .await
+            .map_err(|_| io::Error::other("lost the connection to the agent"))?
+    }
+}
+
+#[async_trait]
+impl Executor for AgentExecutor {
+    async fn output(&self, operation: &Operation) -> io::Result<CommandOutput> {
+        if operation.stream() != Stream::None {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the agent only streams a send or receive",
+            ));
+        }
+        let (_, response) = self.request(operation).await?;
+        exited(response)
+    }
+
+    async fn spawn(
+        &self,
+        operation: &Operation,
+        stdin: bool,
+        stdout: bool,
+    ) -> io::Result<Box<dyn RunningCommand>> {
+        // the agent decides what to stream from the operation
+        let stream = operation.stream();
+        if (stream == Stream::Stdin) != stdin || (stream == Stream::Stdout) != stdout {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the agent only streams the stdout of a send or the stdin of a receive",
+            ));
+        }
+        let (mut conn, response) = self.request(operation).await?;
+        if !matches!(response, AgentResponse::Started) {
+            // it couldn't be started, which `wait` reports
+            let (tx, exited_rx) = oneshot::channel();
+            let _ = tx.send(exited(response));
+            return Ok(Box::new(AgentCommand {
+                stdin: None,
+                stdout: None,
+                exited: exited_rx,
+            }));
+        }
+
+        let (ours, theirs) = tokio::io::duplex(65536);
+        let (tx, exited_rx) = oneshot::channel();
+        let inactivity = Duration::from_secs(self.timeouts.transfer_inactivity);
+        tokio::spawn(async move {
+            let result = match stream {
+                Stream::Stdin => send_stream_and_wait(conn, ours, inactivity).await,
+                Stream::Stdout => match receive_stream(&mut conn, ours, inactivity).await {
+                    Ok(()) => within(inactivity, agent::read_message(&mut conn))
+                        .await
+                        .and_then(exited),
+                    Err(e) => Err(e),
+                },
+                Stream::None => within(inactivity, agent::read_message(&mut conn))
+                    .await
+                    .and_then(exited),
+            };
+            let _ = tx.send(result);
+        });
+
+        let (stdin, stdout) = match stream {
+            Stream::Stdin => (Some(Box::new(theirs) as ByteWriter), None),
+            Stream::Stdout => (None, Some(Box::new(theirs) as ByteReader)),
+            Stream::None => (None, None),
+        };
+        Ok(Box::new(AgentCommand {
+            stdin,
+            stdout,
+            exited: exited_rx,
+        }))
+    }
+
+    fn privilege(&self) -> &Privilege {
+        &self.privilege
+    }
+
+    fn server(&self) -> &str {
+        &self.server
+    }
+
+    fn timeouts(&self) -> &Timeouts {
+        &self.timeouts
+    }
+}
@@ -0,0 +1,94 @@
+use std::{io, process::Stdio};
+
+use async_trait::async_trait;
+use brig_common::agent::Operation;
+use tokio::process::{Child, Command};
+
+use super::{ByteReader, ByteWriter, CommandOutput, Executor, RunningCommand};
+use crate::config::{server::Privilege, timeouts::Timeouts};
+
+/// Runs commands as child processes of brig, for the pool on brig's own host
+pub struct LocalExecutor {
+    pub server: String,
+    pub privilege: Privilege,
+    pub timeouts: Timeouts,
+}
+
+struct LocalCommand(Child);
+
+#[async_trait]
+impl RunningCommand for LocalCommand {
+    fn take_stdin(&mut self) -> Option<ByteWriter> {
+        self.0
+            .stdin
+            .take()
+            .map(|stdin| Box::new(stdin) as ByteWriter)
+    }
+
+    fn take_stdout(&mut self) -> Option<ByteReader> {
+        self.0
+            .stdout
+            .take()
+            .map(|stdout| Box::new(stdout) as ByteReader)
+    }
+
+    async fn wait(self: Box<Self>) -> io::Result<CommandOutput> {
+        let output = self.0.wait_with_output().await?;
+        Ok(CommandOutput {
+            status: output.status.code(),
+            stdout: output.stdout,
+            stderr: output.stderr,
+        })
+    }
+}
+
+fn piped_if(piped: bool) -> Stdio {
+    if piped { Stdio::piped() } else { Stdio::null() }
+}
+
+#[async_trait]
+impl Executor for LocalExecutor {
+    async fn output(&self, operation: &Operation) -> io::Result<CommandOutput> {
+        let (program, args) = super::command_line(&self.privilege, operation);
+        // killed if brig stops waiting for it, e.g. after a timeout
+        let output = Command::new(program)
+            .args(args)
+            .kill_on_drop(true)
+            .output()
+            .await?;
+        Ok(CommandOutput {
+            status: output.status.code(),
+            stdout: output.stdout,
+            stderr: output.stderr,
+        })
+    }
+
+    async fn spawn(
+        &self,
+        operation: &Operation,
+        stdin: bool,
+        stdout: bool,
+    ) -> io::Result<Box<dyn RunningCommand>> {
+        let (program, args) = super::command_line(&self.privilege, operation);
+        let child = Command::new(program)
+            .args(args)
+            .stdin(piped_if(stdin))
+            .stdout(piped_if(stdout))
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        Ok(Box::new(LocalCommand(child)))
+    }
+
+    fn privilege(&self) -> &Privilege {
+        &self.privilege
+    }
+
+    fn server(&self) -> &str {
+        &self.server
+    }
+
+    fn timeouts(&self) -> &Timeouts {
+        &self.timeouts
+    }
+}
@@ -0,0 +1,269 @@
+//! Where brig's zfs commands actually run: over SSH or, for the host brig
+//! itself runs on, as local processes.
+
+pub mod agent;
+pub mod local;
+pub mod pool;
+pub mod ssh;
+
+use std::{
+    io,
+    path::Path,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use brig_common::{agent::Operation, api::api::ErrorCode};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    ConnectorRef,
+    config::{
+        backup,
+        config::Config,
+        server::{Backend, Privilege, Server},
+        timeouts::Timeouts,
+    },
+};
+
+/// Exit code and output of a command that ran to completion
+pub struct CommandOutput {
+    /// `None` if the command was killed by a signal
+    pub status: Option<i32>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        self.status == Some(0)
+    }
+}
+
+pub type ByteReader = Box<dyn AsyncRead + Send + Unpin>;
+pub type ByteWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// A command that is still running, so its stdin and stdout can be streamed,
+/// e.g. from `zfs send` into `zfs recv`
+#[async_trait]
+pub trait RunningCommand: Send {
+    fn take_stdin(&mut self) -> Option<ByteWriter>;
+    fn take_stdout(&mut self) -> Option<ByteReader>;
+    /// Waits for the command to exit, returning whatever it wrote to stderr
+    async fn wait(self: Box<Self>) -> io::Result<CommandOutput>;
+}
+
+/// Carries out operations on one server. Arguments are passed as-is, never
+/// through a shell.
+#[async_trait]
+pub trait Executor: Send + Sync {
+    async fn output(&self, operation: &Operation) -> io::Result<CommandOutput>;
+
+    /// Starts an operation with its stdin and/or stdout piped
+    async fn spawn(
+        &self,
+        operation: &Operation,
+        stdin: bool,
+        stdout: bool,
+    ) -> io::Result<Box<dyn RunningCommand>>;
+
+    /// How commands that need root, see `privileged`, are run
+    fn privilege(&self) -> &Privilege;
+
+    /// Name of the server commands run on, for errors
+    fn server(&self) -> &str;
+
+    /// The timeouts in the config this executor was connected with
+    fn timeouts(&self) -> &Timeouts;
+}
+
+pub type ExecutorRef = Arc<dyn Executor>;
+
+/// The program and arguments that carry out `operation` with `privilege`, for
+/// the backends that run commands themselves
+pub fn command_line(privilege: &Privilege, operation: &Operation) -> (String, Vec<String>) {
+    let (program, args) = operation.command();
+    if !operation.privileged() {
+        return (program.to_owned(), args);
+    }
+    let (program, mut wrapped) = privilege.wrap(program);
+    wrapped.extend(args);
+    (program, wrapped)
+}
+
+/// An operation being put together for an `Executor`
+pub struct Command<'a> {
+    executor: &'a (dyn Executor + 'a),
+    operation: Operation,
+    stdin: bool,
+    stdout: bool,
+    timeout: Option<Duration>,
+}
+
+impl<'e> dyn Executor + 'e {
+    pub fn command(&self, operation: Operation) -> Command<'_> {
+        Command {
+            executor: self,
+            operation,
+            stdin: false,
+            stdout: false,
+            timeout: None,
+        }
+    }
+}
+
+impl Command<'_> {
+    pub fn piped_stdin(mut self) -> Self {
+        self.stdin = true;
+        self
+    }
+
+    pub fn piped_stdout(mut self) -> Self {
+        self.stdout = true;
+        self
+    }
+
+    /// Fails `output` with `io::ErrorKind::TimedOut` if the command hasn't
+    /// exited after `timeout`. Spawned commands aren't limited.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Limits `output` to `timeouts.command`, for commands that take about as
+    /// long however much the pool holds
+    pub fn short(self) -> Self {
+        let timeout = Duration::from_secs(self.executor.timeouts().command);
+        self.timeout(timeout)
+    }
+
+    /// Limits `output` to `timeouts.long_command`, for commands that take
+    /// longer the more the pool holds. A command that times out over SSH may
+    /// keep running on the server.
+    pub fn long(self) -> Self {
+        let timeout = Duration::from_secs(self.executor.timeouts().long_command);
+        self.timeout(timeout)
+    }
+
+    /// The command as it would be typed, for errors
+    pub fn line(&self) -> String {
+        let (program, args) = command_line(self.executor.privilege(), &self.operation);
+        std::iter::once(program)
+            .chain(args)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Runs the command to completion, failing with
+    /// `ErrorCode::ZfsCommandError` unless it exits with 0
+    pub async fn checked_output(self) -> Result<CommandOutput, ErrorCode> {
+        let server = self.executor.server().to_owned();
+        let line = self.line();
+        match self.output().await {
+            Ok(output) if output.success() => Ok(output),
+            Ok(output) => Err(failed(&server, &line, &output)),
+            Err(err) => Err(not_run(&server, &line, &err)),
+        }
+    }
+
+    pub async fn output(self) -> io::Result<CommandOutput> {
+        let output = self.executor.output(&self.operation);
+        let Some(timeout) = self.timeout else {
+            return output.await;
+        };
+        tokio::time::timeout(timeout, output)
+            .await
+            .unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("{} timed out after {}s", self.line(), timeout.as_secs()),
+                ))
+            })
+    }
+
+    pub async fn spawn(self) -> io::Result<Box<dyn RunningCommand>> {
+        self.executor
+            .spawn(&self.operation, self.stdin, self.stdout)
+            .await
+    }
+}
+
+/// `ErrorCode::ZfsCommandError` for a command that exited, but not with 0
+pub fn failed(server: &str, command: &str, output: &CommandOutput) -> ErrorCode {
+    ErrorCode::ZfsCommandError {
+        server: server.to_owned(),
+        command: command.to_owned(),
+        exit_code: output.status,
+        stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        msg: match output.status {
+            Some(status) => format!("exited with status {}", status),
+            None => "killed by a signal".to_owned(),
+        },
+    }
+}
+
+/// `ErrorCode::ZfsCommandError` for a command that couldn't be started or
+/// waited for, or that timed out
+pub fn not_run(server: &str, command: &str, err: &io::Error) -> ErrorCode {
+    ErrorCode::ZfsCommandError {
+        server: server.to_owned(),
+        command: command.to_owned(),
+        exit_code: None,
+        stderr: String::new(),
+        msg: err.to_string(),
+    }
+}
+
+/// How brig reaches its servers, shared by every request and background task
+pub struct Connector {
+    sessions: pool::SessionPool,
+    timeouts: StdMutex<Timeouts>,
+}
+
+impl Connector {
+    /// Keys of servers with a pinned fingerprint are remembered next to the
+    /// config at `config_path`
+    pub fn new(config_path: &Path, config: &Config) -> Self {
+        Self {
+            sessions: pool::SessionPool::new(
+                &config.ssh_pool,
+                backup::with_suffix(config_path, ".known_hosts"),
+            ),
+            timeouts: StdMutex::new(config.timeouts.clone()),
+        }
+    }
+
+    /// Applies the settings of a config that replaced the current one.
+    /// Executors connected before keep the timeouts they were connected with.
+    pub fn configure(&self, config: &Config) {
+        self.sessions.configure(&config.ssh_pool);
+        *self.timeouts.lock().unwrap() = config.timeouts.clone();
+    }
+
+    /// Connects to `server` using the backend it's configured with
+    pub async fn connect(&self, server: &Server) -> Result<ExecutorRef, ErrorCode> {
+        let timeouts = self.timeouts.lock().unwrap().clone();
+        match server.backend {
+            Backend::Ssh => Ok(Arc::new(
+                ssh::SshExecutor::connect(server, &self.sessions, timeouts).await?,
+            )),
+            Backend::Agent => Ok(Arc::new(
+                agent::AgentExecutor::connect(server, timeouts).await?,
+            )),
+            Backend::Local => Ok(Arc::new(local::LocalExecutor {
+                server: server.name.clone(),
+                privilege: server.privilege(),
+                timeouts,
+            })),
+        }
+    }
+}
+
+/// Periodically closes idle SSH sessions
+pub async fn expire_idle_sessions(connector: ConnectorRef) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        connector.sessions.expire_idle().await;
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, Instant},
+};
+
+use brig_common::api::api::ErrorCode;
+use openssh::Session;
+use tokio::sync::Notify;
+
+use super::ssh;
+use crate::config::{server::Server, ssh::SshPool, timeouts::Timeouts};
+
+struct PooledSession {
+    session: Arc<Session>,
+    last_used: Instant,
+}
+
+impl PooledSession {
+    /// Commands and executors hold a clone of the session while they use it
+    fn users(&self) -> usize {
+        Arc::strong_count(&self.session) - 1
+    }
+}
+
+#[derive(Default)]
+struct Host {
+    sessions: Vec<PooledSession>,
+    /// sessions being opened, counted against the cap so concurrent requests
+    /// don't each start a handshake
+    connecting: usize,
+}
+
+/// SSH sessions shared by everything that talks to a server, so each
+/// request doesn't pay for a new handshake
+pub struct SessionPool {
+    settings: StdMutex<SshPool>,
+    /// where the keys of servers with a pinned fingerprint are remembered
+    known_hosts_dir: PathBuf,
+    hosts: StdMutex<HashMap<String, Host>>,
+    /// woken whenever a connect finishes, for requests waiting for a slot
+    connected: Notify,
+}
+
+/// A slot taken for a session being opened, given back when the connect
+/// finishes or is abandoned
+struct Reservation<'a> {
+    pool: &'a SessionPool,
+    key: String,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if let Some(host) = self.pool.hosts.lock().unwrap().get_mut(&self.key) {
+            host.connecting -= 1;
+        }
+        self.pool.connected.notify_waiters();
+    }
+}
+
+/// Sessions are only shared between servers that connect the same way
+fn pool_key(server: &Server) -> String {
+    format!("{}@{} {:?}", &server.user, &server.address, &server.ssh)
+}
+
+impl SessionPool {
+    pub fn new(settings: &SshPool, known_hosts_dir: PathBuf) -> Self {
+        Self {
+            settings: StdMutex::new(settings.clone()),
+            known_hosts_dir,
+            hosts: StdMutex::new(HashMap::new()),
+            connected: Notify::new(),
+        }
+    }
+
+    /// Replaces the limits. Sessions open beyond a lowered cap are kept until
+    /// they've been idle for `idle_timeout`.
+    pub fn configure(&self, settings: &SshPool) {
+        *self.settings.lock().unwrap() = settings.clone();
+    }
+
+    fn max_sessions_per_host(&self) -> usize {
+        self.settings.lock().unwrap().max_sessions_per_host
+    }
+
+    /// The least busy healthy session to `server`. A new session is opened if
+    /// all of them are in use and the server is below its cap, or if none of
+    /// them passes the health check. Requests that find every slot taken by
+    /// a session still connecting wait for it instead of connecting too.
+    pub async fn get(
+        &self,
+        server: &Server,
+        timeouts: &Timeouts,
+    ) -> Result<Arc<Session>, ErrorCode> {
+        let key = pool_key(server);
+        let reservation = loop {
+            let notified = self.connected.notified();
+            tokio::pin!(notified);
+            let candidate = {
+                let mut hosts = self.hosts.lock().unwrap();
+                let host = hosts.entry(key.clone()).or_default();
+                let full = host.sessions.len() + host.connecting >= self.max_sessions_per_host();
+                let candidate = host
+                    .sessions
+                    .iter_mut()
+                    .min_by_key(|pooled| pooled.users())
+                    .filter(|pooled| pooled.users() == 0 || full)
+                    .map(|pooled| {
+                        pooled.last_used = Instant::now();
+                        pooled.session.clone()
+                    });
+                if candidate.is_none() && !full {
+                    host.connecting += 1;
+                    break Reservation {
+                        pool: self,
+                        key: key.clone(),
+                    };
+                }
+                // registered before the lock is released so a connect
+                // finishing in between isn't missed
+                notified.as_mut().enable();
+                candidate
+            };
+            let Some(session) = candidate else {
+                notified.await;
+                continue;
+            };
+            let timeout = Duration::from_secs(timeouts.command);
+            if tokio::time::timeout(timeout, session.check())
+                .await
+                .is_ok_and(|checked| checked.is_ok())
+            {
+                return Ok(session);
+            }
+            println!("ssh session to {} is broken, reconnecting", &server.name);
+            if let Some(host) = self.hosts.lock().unwrap().get_mut(&key) {
+                host.sessions
+                    .retain(|pooled| !Arc::ptr_eq(&pooled.session, &session));
+            }
+        };
+
+        let session = Arc::new(ssh::open_session(server, timeouts, &self.known_hosts_dir).await?);
+        self.hosts
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_default()
+            .sessions
+            .push(PooledSession {
+                session: session.clone(),
+                last_used: Instant::now(),
+            });
+        drop(reservation);
+        Ok(session)
+    }
+
+    /// Closes sessions that nothing has used for `idle_timeout`
+    pub async fn expire_idle(&self) {
+        let idle_timeout = Duration::from_secs(self.settings.lock().unwrap().idle_timeout);
+        let mut expired = vec![];
+        {
+            let mut hosts = self.hosts.lock().unwrap();
+            for host in hosts.values_mut() {
+                let (idle, active): (Vec<_>, Vec<_>) =
+                    host.sessions.drain(..).partition(|pooled| {
+                        pooled.users() == 0 && pooled.last_used.elapsed() >= idle_timeout
+                    });
+                host.sessions = active;
+                expired.extend(idle);
+            }
+            hosts.retain(|_, host| !host.sessions.is_empty() || host.connecting > 0);
+        }
+        for pooled in expired {
+            if let Ok(session) = Arc::try_unwrap(pooled.session) {
+                let _ = session.close().await;
+            }
+        }
+    }
+}
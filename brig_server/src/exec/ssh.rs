@@ -0,0 +1,359 @@
+use std::{
+    fs::Metadata,
+    io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use brig_common::{agent::Operation, api::api::ErrorCode};
+use openssh::{Child, KnownHosts, Session, SessionBuilder, Stdio};
+use tokio::io::AsyncWriteExt;
+
+use super::{ByteReader, ByteWriter, CommandOutput, Executor, RunningCommand, pool::SessionPool};
+use crate::config::{
+    server::{Privilege, Server},
+    ssh::KnownHostsMode,
+    timeouts::Timeouts,
+};
+
+/// Runs commands on a server over a multiplexed SSH connection from the pool
+pub struct SshExecutor {
+    session: Arc<Session>,
+    server: String,
+    privilege: Privilege,
+    timeouts: Timeouts,
+}
+
+impl SshExecutor {
+    pub async fn connect(
+        server: &Server,
+        sessions: &SessionPool,
+        timeouts: Timeouts,
+    ) -> Result<Self, ErrorCode> {
+        Ok(Self {
+            session: sessions.get(server, &timeouts).await?,
+            server: server.name.clone(),
+            privilege: server.privilege(),
+            timeouts,
+        })
+    }
+}
+
+/// Where the key of a server with a pinned fingerprint is remembered once it
+/// has been checked. The name is hex encoded so no two servers share a file.
+fn pinned_known_hosts(known_hosts_dir: &Path, server: &Server) -> PathBuf {
+    let name: String = server
+        .name
+        .bytes()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    known_hosts_dir.join(name)
+}
+
+/// Anyone else who could write to a pin could put their own key in it, so
+/// it's only trusted if it belongs to brig's user alone
+fn check_private(server: &Server, path: &Path, metadata: &Metadata) -> Result<(), ErrorCode> {
+    let reason = if metadata.file_type().is_symlink() {
+        "is a symlink"
+    } else if metadata.uid() != unsafe { libc::geteuid() } {
+        "is owned by another user"
+    } else if metadata.mode() & 0o022 != 0 {
+        "is writable by group or others"
+    } else {
+        return Ok(());
+    };
+    Err(ErrorCode::UnsafeKnownHosts {
+        server: server.name.clone(),
+        path: path.display().to_string(),
+        reason: reason.to_owned(),
+    })
+}
+
+/// Creates the directory pins are kept in, accessible to brig's user only
+async fn prepare_known_hosts_dir(server: &Server, dir: &Path) -> Result<(), ErrorCode> {
+    let unsafe_dir = |err: io::Error| ErrorCode::UnsafeKnownHosts {
+        server: server.name.clone(),
+        path: dir.display().to_string(),
+        reason: err.to_string(),
+    };
+    let mut builder = tokio::fs::DirBuilder::new();
+    builder.mode(0o700);
+    match builder.create(dir).await {
+        Err(err) if err.kind() != io::ErrorKind::AlreadyExists => return Err(unsafe_dir(err)),
+        _ => {}
+    }
+    let metadata = tokio::fs::symlink_metadata(dir).await.map_err(unsafe_dir)?;
+    check_private(server, dir, &metadata)
+}
+
+/// SHA256 fingerprints of the keys in a known_hosts file
+async fn fingerprints(known_hosts: &Path) -> Vec<String> {
+    let Ok(output) = tokio::process::Command::new("ssh-keygen")
+        .arg("-l")
+        .arg("-f")
+        .arg(known_hosts)
+        .output()
+        .await
+    else {
+        return vec![];
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(String::from)
+        .collect()
+}
+
+/// SHA256 fingerprint of a single known_hosts line
+async fn fingerprint(key: &str) -> Option<String> {
+    let mut child = tokio::process::Command::new("ssh-keygen")
+        .args(["-l", "-f", "-"])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .ok()?;
+    let mut stdin = child.stdin.take()?;
+    stdin
+        .write_all(format!("{}\n", key).as_bytes())
+        .await
+        .ok()?;
+    drop(stdin);
+    let output = child.wait_with_output().await.ok()?;
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .nth(1)
+        .map(String::from)
+}
+
+/// The keys `server` presents, as known_hosts lines. Only the key exchange
+/// is done, nothing is sent that would authenticate.
+async fn scan_host_keys(server: &Server, timeout: u64) -> Vec<String> {
+    let mut command = tokio::process::Command::new("ssh-keyscan");
+    command.arg("-T").arg(timeout.to_string());
+    if let Some(port) = server.ssh.port {
+        command.arg("-p").arg(port.to_string());
+    }
+    command
+        .arg(&server.address)
+        .stderr(std::process::Stdio::null())
+        .kill_on_drop(true);
+    let Ok(Ok(output)) = tokio::time::timeout(Duration::from_secs(timeout), command.output()).await
+    else {
+        return vec![];
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+/// Fetches the keys of `server` and remembers the ones matching
+/// `fingerprint` in `known_hosts`, so ssh is only ever started against a
+/// key that was checked first
+async fn pin_host_key(
+    server: &Server,
+    fingerprint: &str,
+    known_hosts: &Path,
+    timeout: u64,
+) -> Result<(), ErrorCode> {
+    let keys = scan_host_keys(server, timeout).await;
+    if keys.is_empty() {
+        println!("ssh-keyscan found no host keys on {}", &server.name);
+        return Err(ErrorCode::SshSessionFail {
+            user: server.user.clone(),
+            ip: server.address.clone(),
+        });
+    }
+    let mut found = vec![];
+    let mut matching = String::new();
+    for key in keys {
+        let Some(key_fingerprint) = self::fingerprint(&key).await else {
+            continue;
+        };
+        if key_fingerprint == fingerprint {
+            matching.push_str(&key);
+            matching.push('\n');
+        }
+        found.push(key_fingerprint);
+    }
+    if matching.is_empty() {
+        return Err(ErrorCode::HostKeyMismatch {
+            server: server.name.clone(),
+            expected: fingerprint.to_owned(),
+            found,
+        });
+    }
+    let unsafe_pin = |err: io::Error| ErrorCode::UnsafeKnownHosts {
+        server: server.name.clone(),
+        path: known_hosts.display().to_string(),
+        reason: err.to_string(),
+    };
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(known_hosts)
+        .await
+        .map_err(unsafe_pin)?;
+    file.write_all(matching.as_bytes())
+        .await
+        .map_err(unsafe_pin)?;
+    Ok(())
+}
+
+/// Opens a new session to `server` with its SSH options. With a pinned
+/// fingerprint, the server's key is fetched and checked before ssh connects,
+/// and ssh then refuses any key but the one remembered in `known_hosts_dir`.
+pub async fn open_session(
+    server: &Server,
+    timeouts: &Timeouts,
+    known_hosts_dir: &Path,
+) -> Result<Session, ErrorCode> {
+    let options = &server.ssh;
+    let mut builder = SessionBuilder::default();
+    builder.user(server.user.clone());
+    if let Some(port) = options.port {
+        builder.port(port);
+    }
+    if let Some(identity_file) = &options.identity_file {
+        builder.keyfile(identity_file);
+    }
+    let timeout = options.connect_timeout.unwrap_or(timeouts.connect);
+    builder.connect_timeout(Duration::from_secs(timeout));
+    builder.server_alive_interval(Duration::from_secs(timeouts.keepalive));
+    if !options.jump_hosts.is_empty() {
+        builder.jump_hosts(&options.jump_hosts);
+    }
+
+    match &options.host_key_fingerprint {
+        Some(fingerprint) => {
+            prepare_known_hosts_dir(server, known_hosts_dir).await?;
+            let known_hosts = pinned_known_hosts(known_hosts_dir, server);
+            let remembered = match tokio::fs::symlink_metadata(&known_hosts).await {
+                Ok(metadata) => {
+                    check_private(server, &known_hosts, &metadata)?;
+                    fingerprints(&known_hosts).await.contains(fingerprint)
+                }
+                Err(_) => false,
+            };
+            // a key remembered under an older pin would make ssh refuse the
+            // new one
+            if !remembered {
+                let _ = tokio::fs::remove_file(&known_hosts).await;
+                pin_host_key(server, fingerprint, &known_hosts, timeout).await?;
+            }
+            builder
+                .user_known_hosts_file(&known_hosts)
+                .known_hosts_check(KnownHosts::Strict);
+        }
+        None => {
+            builder.known_hosts_check(match options.known_hosts {
+                KnownHostsMode::Strict => KnownHosts::Strict,
+                KnownHostsMode::AcceptNew => KnownHosts::Add,
+                KnownHostsMode::Accept => KnownHosts::Accept,
+            });
+        }
+    }
+
+    // ssh's ConnectTimeout only covers the TCP connection, not a handshake
+    // that stalls
+    let connecting = builder.connect(&server.address);
+    let session = tokio::time::timeout(Duration::from_secs(timeout), connecting)
+        .await
+        .map_err(|_| ErrorCode::ConnectTimeout {
+            server: server.name.clone(),
+            seconds: timeout,
+        })?
+        .map_err(|err| {
+            println!("ssh connection to {} failed: {}", &server.name, err);
+            ErrorCode::SshSessionFail {
+                user: server.user.clone(),
+                ip: server.address.clone(),
+            }
+        })?;
+    Ok(session)
+}
+
+struct SshCommand(Child<Arc<Session>>);
+
+#[async_trait]
+impl RunningCommand for SshCommand {
+    fn take_stdin(&mut self) -> Option<ByteWriter> {
+        self.0
+            .stdin()
+            .take()
+            .map(|stdin| Box::new(stdin) as ByteWriter)
+    }
+
+    fn take_stdout(&mut self) -> Option<ByteReader> {
+        self.0
+            .stdout()
+            .take()
+            .map(|stdout| Box::new(stdout) as ByteReader)
+    }
+
+    async fn wait(self: Box<Self>) -> io::Result<CommandOutput> {
+        let output = self.0.wait_with_output().await.map_err(io::Error::other)?;
+        Ok(CommandOutput {
+            status: output.status.code(),
+            stdout: output.stdout,
+            stderr: output.stderr,
+        })
+    }
+}
+
+fn piped_if(piped: bool) -> Stdio {
+    if piped { Stdio::piped() } else { Stdio::null() }
+}
+
+#[async_trait]
+impl Executor for SshExecutor {
+    async fn output(&self, operation: &Operation) -> io::Result<CommandOutput> {
+        let (program, args) = super::command_line(&self.privilege, operation);
+        let output = Session::arc_command(self.session.clone(), program)
+            .args(args)
+            .output()
+            .await
+            .map_err(io::Error::other)?;
+        Ok(CommandOutput {
+            status: output.status.code(),
+            stdout: output.stdout,
+            stderr: output.stderr,
+        })
+    }
+
+    async fn spawn(
+        &self,
+        operation: &Operation,
+        stdin: bool,
+        stdout: bool,
+    ) -> io::Result<Box<dyn RunningCommand>> {
+        let (program, args) = super::command_line(&self.privilege, operation);
+        let child = Session::arc_command(self.session.clone(), program)
+            .args(args)
+            .stdin(piped_if(stdin))
+            .stdout(piped_if(stdout))
+            .stderr(Stdio::piped())
+            .spawn()
+            .await
+            .map_err(io::Error::other)?;
+        Ok(Box::new(SshCommand(child)))
+    }
+
+    fn privilege(&self) -> &Privilege {
+        &self.privilege
+    }
+
+    fn server(&self) -> &str {
+        &self.server
+    }
+
+    fn timeouts(&self) -> &Timeouts {
+        &self.timeouts
+    }
+}
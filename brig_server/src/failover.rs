@@ -0,0 +1,505 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use brig_common::api::api::ErrorCode;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::{
+    ConfigRef, ConnectorRef, Failovers, JobRef, Jobs, Leases, ServerHealths,
+    config::{backup, config::Config, dataset::Dataset, mount::MountRole, server::Server},
+    job, mount, probe, utils,
+    zfs::{self, Snapshot},
+};
+
+/// A replica that was promoted because the owner stopped responding
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Failover {
+    pub dataset: String,
+    pub old_server: String,
+    pub new_server: String,
+    /// the newest snapshot the new owner shared with the old owner, anything
+    /// written on the old owner after it may be lost
+    pub last_snapshot: String,
+    pub last_snapshot_created: Option<DateTime<Utc>>,
+    pub at: DateTime<Utc>,
+    /// whether the old owner has been set read-only since it came back
+    pub fenced: bool,
+    /// snapshots found on the old owner when it was fenced that never made it
+    /// to the new owner
+    pub unreplicated_snapshots: Vec<String>,
+    /// whether an operator accepted that `unreplicated_snapshots` are lost,
+    /// the old owner isn't synced to before that
+    #[serde(default)]
+    pub acknowledged: bool,
+}
+
+/// Failovers brig has done, kept in a file next to the config so an old owner
+/// is still fenced when it comes back after brig restarted
+pub struct FailoverLog {
+    path: PathBuf,
+    failovers: RwLock<Vec<Failover>>,
+}
+
+impl FailoverLog {
+    pub fn load(config_path: &Path) -> Result<Self> {
+        let path = backup::with_suffix(config_path, ".failovers");
+
+        let failovers = if path.exists() {
+            let contents = std::fs::read_to_string(&path)
+                .with_context(|| format!("unable to read failover file {}", path.display()))?;
+            serde_json::from_str(&contents)
+                .with_context(|| format!("invalid failover file {}", path.display()))?
+        } else {
+            vec![]
+        };
+        Ok(Self {
+            path,
+            failovers: RwLock::new(failovers),
+        })
+    }
+
+    fn save(&self, failovers: &[Failover]) -> Result<(), ErrorCode> {
+        let json_str =
+            serde_json::to_string_pretty(failovers).map_err(|_| ErrorCode::ConfigIsInvalidJson)?;
+        backup::write_atomic(&self.path, &json_str)
+    }
+
+    pub async fn list(&self) -> Vec<Failover> {
+        self.failovers.read().await.clone()
+    }
+
+    pub async fn add(&self, failover: Failover) -> Result<(), ErrorCode> {
+        let mut failovers = self.failovers.write().await;
+        failovers.push(failover);
+        self.save(&failovers)
+    }
+
+    /// Forgets a failover whose promotion didn't go through
+    async fn remove(&self, failover: &Failover) -> Result<(), ErrorCode> {
+        let mut failovers = self.failovers.write().await;
+        failovers
+            .retain(|existing| existing.dataset != failover.dataset || existing.at != failover.at);
+        self.save(&failovers)
+    }
+
+    /// Marks the failovers of `dataset` away from `server` as acknowledged
+    pub async fn acknowledge(&self, dataset: &str, server: &str) -> Result<(), ErrorCode> {
+        let mut failovers = self.failovers.write().await;
+        for failover in failovers.iter_mut() {
+            if failover.dataset == dataset && failover.old_server == server {
+                failover.acknowledged = true;
+            }
+        }
+        self.save(&failovers)
+    }
+
+    /// Replaces the failover of the same dataset at the same time with
+    /// `failover` and persists the result
+    async fn update(&self, failover: Failover) -> Result<(), ErrorCode> {
+        let mut failovers = self.failovers.write().await;
+        if let Some(existing) = failovers
+            .iter_mut()
+            .find(|existing| existing.dataset == failover.dataset && existing.at == failover.at)
+        {
+            *existing = failover;
+        }
+        self.save(&failovers)
+    }
+}
+
+/// Snapshots of `dataset` on `server`, newest first, or `None` if it can't be
+/// reached or doesn't answer within `timeouts.command`. The monitor checks
+/// every dataset in turn, so a hung server mustn't hold up the others.
+async fn list_within(
+    config: &Config,
+    connector: &ConnectorRef,
+    server: &Server,
+    dataset: &str,
+) -> Option<Vec<Snapshot>> {
+    let listing = async {
+        let session = connector.connect(server).await?;
+        zfs::snapshots(&session, &server.pool, dataset).await
+    };
+    match tokio::time::timeout(Duration::from_secs(config.timeouts.command), listing).await {
+        Ok(Ok(snapshots)) => Some(snapshots),
+        Ok(Err(e)) => {
+            println!("unable to list {} on {}: {:?}", dataset, &server.name, &e);
+            None
+        }
+        Err(_) => {
+            println!("listing {} on {} timed out", dataset, &server.name);
+            None
+        }
+    }
+}
+
+/// The replica to promote and the owner's snapshot it was last synced to
+struct Candidate<'a> {
+    server: &'a Server,
+    snapshot: Snapshot,
+}
+
+/// The reachable replica that shares the newest snapshot with what the owner
+/// last had, `owner_snapshots`, compared by guid. If the owner hasn't been
+/// seen since brig started, the replica with the newest snapshot is picked
+/// instead. Replicas the last probe found unhealthy aren't considered.
+async fn pick_replica<'a>(
+    config: &'a Config,
+    connector: &ConnectorRef,
+    dataset: &Dataset,
+    healths: &ServerHealths,
+    owner_snapshots: &[Snapshot],
+) -> Result<Candidate<'a>, ErrorCode> {
+    if owner_snapshots.is_empty() {
+        println!(
+            "the snapshots of the owner of {} are unknown, picking the replica with the newest snapshot",
+            &dataset.name
+        );
+    }
+    // owner snapshots are newest first, so a lower position is more recent
+    let mut best: Option<(usize, Candidate)> = None;
+    for server in &config.servers {
+        if server.name == dataset.server {
+            continue;
+        }
+        if probe::is_unhealthy(healths, &server.name).await {
+            println!("skipping unhealthy failover candidate {}", &server.name);
+            continue;
+        }
+        let Some(snapshots) = list_within(config, connector, server, &dataset.name).await else {
+            println!("failover candidate {} is unreachable", &server.name);
+            continue;
+        };
+        let found = if owner_snapshots.is_empty() {
+            snapshots.into_iter().next().map(|latest| (0, latest))
+        } else {
+            owner_snapshots
+                .iter()
+                .enumerate()
+                .find_map(|(position, owned)| {
+                    snapshots
+                        .iter()
+                        .find(|snapshot| snapshot.guid == owned.guid)
+                        .map(|snapshot| (position, snapshot.clone()))
+                })
+        };
+        let Some((position, snapshot)) = found else {
+            println!(
+                "failover candidate {} has no snapshot in common with the owner",
+                &server.name
+            );
+            continue;
+        };
+        let better = match &best {
+            None => true,
+            Some((_, best)) if owner_snapshots.is_empty() => {
+                snapshot.created() > best.snapshot.created()
+            }
+            Some((best_position, _)) => position < *best_position,
+        };
+        if better {
+            best = Some((position, Candidate { server, snapshot }));
+        }
+    }
+    best.map(|(_, candidate)| candidate)
+        .ok_or(ErrorCode::NoFailoverCandidate {
+            dataset: dataset.name.clone(),
+        })
+}
+
+/// Makes `new_server` the owner of `dataset` in the config and fences the old
+/// owner in the same write, so the old owner isn't synced to before the
+/// failover is acknowledged
+async fn set_failed_over(
+    config_path: &Path,
+    config_arc: &ConfigRef,
+    dataset: &str,
+    new_server: &str,
+) -> Result<(), ErrorCode> {
+    let mut config = config_arc.write().await;
+    let pos = config
+        .datasets
+        .iter()
+        .position(|ds: &Dataset| ds.name == dataset)
+        .ok_or(ErrorCode::DatasetNotFoundInConfig {
+            dataset: dataset.to_owned(),
+        })?;
+    let previous = config.datasets[pos].clone();
+    let updated = &mut config.datasets[pos];
+    let old_server = std::mem::replace(&mut updated.server, new_server.to_owned());
+    if !updated.fenced_servers.contains(&old_server) {
+        updated.fenced_servers.push(old_server);
+    }
+    if let Err(e) = config.save(config_path) {
+        config.datasets[pos] = previous;
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Makes `new_server` writable and the owner of `dataset`. The failover is
+/// already recorded, so the old owner is fenced after a restart too.
+async fn promote(
+    config_path: &Path,
+    config_arc: &ConfigRef,
+    connector: &ConnectorRef,
+    job: &JobRef,
+    dataset: &Dataset,
+    new_server: &Server,
+) -> Result<(), ErrorCode> {
+    let session = connector.connect(new_server).await?;
+    utils::set_readonly(&session, new_server, &dataset.name, false).await?;
+    job::log_step(job, format!("set writable on {}", &new_server.name)).await;
+
+    if let Err(e) = set_failed_over(config_path, config_arc, &dataset.name, &new_server.name).await
+    {
+        // the old owner is still the owner, so the replica mustn't take writes
+        if let Err(e) = utils::set_readonly(&session, new_server, &dataset.name, true).await {
+            println!(
+                "failed to set {} read-only again on {}: {:?}",
+                &dataset.name, &new_server.name, &e
+            );
+        }
+        return Err(e);
+    }
+    job::log_step(job, format!("{} is now the owner", &new_server.name)).await;
+
+    if let Some(policy) = &dataset.mount {
+        match mount::enforce(&session, new_server, dataset, policy, MountRole::Owner).await {
+            Ok(()) => job::log_step(job, format!("mounted on {}", &new_server.name)).await,
+            Err(e) => {
+                job::log_step(
+                    job,
+                    format!("failed to mount on {}: {:?}", &new_server.name, &e),
+                )
+                .await
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Records the failover to `candidate` and only then promotes it. If the
+/// record can't be written nothing is promoted.
+async fn fail_over(
+    config_path: &Path,
+    config_arc: &ConfigRef,
+    connector: &ConnectorRef,
+    failovers: &Failovers,
+    job: &JobRef,
+    dataset: &Dataset,
+    candidate: Candidate<'_>,
+) -> Result<(), ErrorCode> {
+    let new_server = candidate.server;
+    job::log_step(
+        job,
+        format!(
+            "promoting {}, last synced to {}",
+            &new_server.name, &candidate.snapshot.name
+        ),
+    )
+    .await;
+
+    let failover = Failover {
+        dataset: dataset.name.clone(),
+        old_server: dataset.server.clone(),
+        new_server: new_server.name.clone(),
+        last_snapshot: candidate.snapshot.short_name().to_owned(),
+        last_snapshot_created: candidate.snapshot.created(),
+        at: Utc::now(),
+        fenced: false,
+        unreplicated_snapshots: vec![],
+        acknowledged: false,
+    };
+    failovers.add(failover.clone()).await?;
+
+    if let Err(e) = promote(config_path, config_arc, connector, job, dataset, new_server).await {
+        if let Err(e) = failovers.remove(&failover).await {
+            println!("failed to save failovers: {:?}", &e);
+        }
+        return Err(e);
+    }
+    job::log_step(
+        job,
+        format!(
+            "anything written on {} after {} may be lost",
+            &failover.old_server, &failover.last_snapshot
+        ),
+    )
+    .await;
+    Ok(())
+}
+
+/// Sets the dataset read-only on an old owner that has come back, so it can't
+/// diverge any further from the new owner
+async fn fence(
+    config: &Config,
+    connector: &ConnectorRef,
+    failover: &mut Failover,
+) -> Result<(), ErrorCode> {
+    let server = config
+        .servers
+        .iter()
+        .find(|server: &&Server| server.name == failover.old_server)
+        .ok_or(ErrorCode::ServerNotFoundFromDataset {
+            dataset: failover.dataset.clone(),
+            server_name: failover.old_server.clone(),
+        })?;
+    let session = connector.connect(server).await?;
+    utils::set_readonly(&session, server, &failover.dataset, true).await?;
+    failover.fenced = true;
+
+    let snapshots = zfs::snapshots(&session, &server.pool, &failover.dataset).await?;
+    failover.unreplicated_snapshots = snapshots
+        .iter()
+        .map(|snapshot| snapshot.short_name().to_owned())
+        .take_while(|snapshot| *snapshot != failover.last_snapshot)
+        .collect();
+    println!(
+        "fenced {} on {}, snapshots that never reached {}: {:?}",
+        &failover.dataset,
+        &failover.old_server,
+        &failover.new_server,
+        &failover.unreplicated_snapshots
+    );
+    Ok(())
+}
+
+/// Health checks the owner of every dataset with a failover policy and
+/// promotes a replica once the owner has failed too many checks in a row.
+pub async fn monitor(
+    config_path: Arc<PathBuf>,
+    config_arc: ConfigRef,
+    jobs: Jobs,
+    failovers: Failovers,
+    leases: Leases,
+    connector: ConnectorRef,
+    healths: ServerHealths,
+) {
+    let mut last_probes: HashMap<String, Instant> = HashMap::new();
+    let mut failed_probes: HashMap<String, u32> = HashMap::new();
+    // what each owner had the last time it answered, to pick the replica
+    // that's closest to it
+    let mut owner_snapshots: HashMap<String, Vec<Snapshot>> = HashMap::new();
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let config = { config_arc.read().await.clone() };
+        for dataset in &config.datasets {
+            let Some(policy) = &dataset.failover else {
+                continue;
+            };
+            if last_probes
+                .get(&dataset.name)
+                .is_some_and(|last| last.elapsed() < Duration::from_secs(policy.probe_interval))
+            {
+                continue;
+            }
+            last_probes.insert(dataset.name.clone(), Instant::now());
+
+            // fence without holding the log, the old owner is probably
+            // still down and connecting to it can take until the timeout
+            let unfenced: Vec<Failover> = failovers
+                .list()
+                .await
+                .into_iter()
+                .filter(|failover| failover.dataset == dataset.name && !failover.fenced)
+                .collect();
+            for mut failover in unfenced {
+                if let Err(e) = fence(&config, &connector, &mut failover).await {
+                    println!(
+                        "unable to fence {} on {} yet: {:?}",
+                        &failover.dataset, &failover.old_server, &e
+                    );
+                    continue;
+                }
+                if let Err(e) = failovers.update(failover).await {
+                    println!("failed to save failovers: {:?}", &e);
+                }
+            }
+
+            let Some(owner) = config
+                .servers
+                .iter()
+                .find(|server: &&Server| server.name == dataset.server)
+            else {
+                continue;
+            };
+            if let Some(snapshots) = list_within(&config, &connector, owner, &dataset.name).await {
+                failed_probes.remove(&dataset.name);
+                owner_snapshots.insert(dataset.name.clone(), snapshots);
+                continue;
+            }
+
+            let failed = failed_probes.entry(dataset.name.clone()).or_insert(0);
+            *failed += 1;
+            println!(
+                "owner {} of dataset {} failed health check {}/{}",
+                &owner.name, &dataset.name, failed, policy.max_failed_probes
+            );
+            if *failed < policy.max_failed_probes {
+                continue;
+            }
+            // don't promote a replica in the middle of a sync or switch, try
+            // again on the next probe
+            let _lease = match leases.try_acquire(&dataset.name, "failover") {
+                Ok(lease) => lease,
+                Err(e) => {
+                    println!("not failing over dataset {} yet: {:?}", &dataset.name, &e);
+                    continue;
+                }
+            };
+            failed_probes.remove(&dataset.name);
+
+            // a switch may have finished since the probe, the owner it
+            // failed over from has to be the current one
+            let current = { config_arc.read().await.clone() };
+            let Some(dataset) = current
+                .datasets
+                .iter()
+                .find(|ds: &&Dataset| ds.name == dataset.name && ds.failover.is_some())
+            else {
+                continue;
+            };
+            if dataset.server != owner.name {
+                println!(
+                    "owner of dataset {} changed to {}, not failing over",
+                    &dataset.name, &dataset.server
+                );
+                continue;
+            }
+
+            let job = job::start_job(&jobs, "failover", &dataset.name).await;
+            let known = owner_snapshots
+                .get(&dataset.name)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            let result = match pick_replica(&current, &connector, dataset, &healths, known).await {
+                Ok(candidate) => {
+                    fail_over(
+                        &config_path,
+                        &config_arc,
+                        &connector,
+                        &failovers,
+                        &job,
+                        dataset,
+                        candidate,
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+            if result.is_ok() {
+                owner_snapshots.remove(&dataset.name);
+            }
+            job::finish_job(&job, result).await;
+        }
+    }
+}
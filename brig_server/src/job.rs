@@ -0,0 +1,75 @@
+use brig_common::api::api::ErrorCode;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::{JobRef, Jobs};
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub enum JobStatus {
+    Running,
+    Succeeded,
+    Failed,
+    /// a transfer completed, but the destination doesn't have what was sent
+    FailedVerification,
+}
+
+/// A long running operation on a dataset, e.g. a planned switch
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Job {
+    pub id: u64,
+    pub kind: String,
+    pub dataset: String,
+    pub status: JobStatus,
+    /// what the job has done so far, in order
+    pub steps: Vec<String>,
+    pub error: Option<ErrorCode>,
+    pub started: DateTime<Utc>,
+    pub finished: Option<DateTime<Utc>>,
+}
+
+pub async fn start_job(jobs: &Jobs, kind: &str, dataset: &str) -> JobRef {
+    let mut jobs = jobs.write().await;
+    let mut id = 1;
+    for job in jobs.iter() {
+        id = id.max(job.read().await.id + 1);
+    }
+    let job = Arc::new(RwLock::new(Job {
+        id,
+        kind: kind.to_owned(),
+        dataset: dataset.to_owned(),
+        status: JobStatus::Running,
+        steps: vec![],
+        error: None,
+        started: Utc::now(),
+        finished: None,
+    }));
+    jobs.push(job.clone());
+    job
+}
+
+pub async fn log_step(job: &JobRef, step: String) {
+    let mut job = job.write().await;
+    println!("job {} ({} {}): {}", job.id, &job.kind, &job.dataset, &step);
+    job.steps.push(step);
+}
+
+pub async fn finish_job(job: &JobRef, result: Result<(), ErrorCode>) {
+    let mut job = job.write().await;
+    job.finished = Some(Utc::now());
+    match result {
+        Ok(()) => job.status = JobStatus::Succeeded,
+        Err(e) => {
+            println!(
+                "job {} ({} {}) failed: {:?}",
+                job.id, &job.kind, &job.dataset, &e
+            );
+            job.status = match e {
+                ErrorCode::ReplicaVerificationFailed { .. } => JobStatus::FailedVerification,
+                _ => JobStatus::Failed,
+            };
+            job.error = Some(e);
+        }
+    }
+}
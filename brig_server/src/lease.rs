@@ -0,0 +1,91 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use brig_common::api::api::ErrorCode;
+use tokio::{sync::Notify, time::Instant};
+
+use crate::Leases;
+
+/// Which mutating operation (sync, clean, switch, ...) currently holds each
+/// dataset. Every operation that changes a dataset takes a lease on it first
+/// so that, e.g., clean can't destroy a snapshot a sync is sending.
+#[derive(Default)]
+pub struct DatasetLeases {
+    held: Mutex<HashMap<String, String>>,
+    released: Notify,
+}
+
+/// Exclusive access to a dataset, given up when dropped
+pub struct Lease {
+    leases: Leases,
+    dataset: String,
+}
+
+impl Lease {
+    pub fn dataset(&self) -> &str {
+        &self.dataset
+    }
+
+    pub fn leases(&self) -> &Leases {
+        &self.leases
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.leases.held.lock().unwrap().remove(&self.dataset);
+        self.leases.released.notify_waiters();
+    }
+}
+
+impl DatasetLeases {
+    /// Takes a lease on `dataset` for `operation` if no other operation holds it
+    pub fn try_acquire(
+        self: &Arc<Self>,
+        dataset: &str,
+        operation: &str,
+    ) -> Result<Lease, ErrorCode> {
+        let mut held = self.held.lock().unwrap();
+        if let Some(current) = held.get(dataset) {
+            return Err(ErrorCode::DatasetBusy {
+                dataset: dataset.to_owned(),
+                operation: current.clone(),
+            });
+        }
+        held.insert(dataset.to_owned(), operation.to_owned());
+        Ok(Lease {
+            leases: self.clone(),
+            dataset: dataset.to_owned(),
+        })
+    }
+
+    /// Takes a lease on `dataset` for `operation`. If another operation holds
+    /// it, waits up to `wait` for it to be released before giving up with
+    /// `ErrorCode::DatasetBusy`.
+    pub async fn acquire(
+        self: &Arc<Self>,
+        dataset: &str,
+        operation: &str,
+        wait: Option<Duration>,
+    ) -> Result<Lease, ErrorCode> {
+        let deadline = Instant::now() + wait.unwrap_or_default();
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            // register before checking so a release in between isn't missed
+            released.as_mut().enable();
+
+            match self.try_acquire(dataset, operation) {
+                Ok(lease) => return Ok(lease),
+                Err(e) => {
+                    if tokio::time::timeout_at(deadline, released).await.is_err() {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,26 +1,53 @@
 mod api;
 mod cli;
 mod config;
+mod exec;
+mod failover;
+mod job;
+mod lease;
+mod mount;
+mod probe;
+mod schedule;
+mod switch_transaction;
 mod sync_state;
 mod utils;
+mod zfs;
 
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use anyhow::Result;
-use brig_common::api::{switch::SwitchRequest, sync::SyncRequest};
+use brig_common::api::{
+    config::RestoreConfigRequest,
+    failover::AcknowledgeFailoverRequest,
+    ownership::AdoptRequest,
+    server::ServerHealth,
+    snapshot::{SnapshotRequest, UnpinRequest},
+    switch::{CancelSwitchRequest, ReadinessRequest, ScheduleSwitchRequest, SwitchRequest},
+    sync::SyncRequest,
+};
 use clap::Parser;
 use cli::Cli;
 use config::config::Config;
-use openssh::Session;
+use exec::Connector;
+use failover::FailoverLog;
+use job::Job;
+use lease::DatasetLeases;
+use schedule::Scheduler;
 use sync_state::SyncState;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::RwLock;
 
 use warp::Filter;
 
-pub type SshSessions = Arc<Mutex<Vec<Session>>>;
 pub type ConfigRef = Arc<RwLock<Config>>;
+pub type ConnectorRef = Arc<Connector>;
 pub type SyncStateRef = Arc<RwLock<SyncState>>;
 pub type SyncStates = Arc<RwLock<Vec<SyncStateRef>>>;
+pub type JobRef = Arc<RwLock<Job>>;
+pub type Jobs = Arc<RwLock<Vec<JobRef>>>;
+pub type Failovers = Arc<FailoverLog>;
+pub type Leases = Arc<DatasetLeases>;
+pub type Schedule = Arc<Scheduler>;
+pub type ServerHealths = Arc<RwLock<HashMap<String, ServerHealth>>>;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -32,8 +59,13 @@ async fn main() -> Result<()> {
         move || config.clone()
     });
 
-    let config = std::fs::read_to_string(&*config_path)?;
-    let config = serde_json::from_str::<Config>(&config)?;
+    let config = Config::load(&config_path)?;
+    let connector: ConnectorRef = Arc::new(Connector::new(&config_path, &config));
+    let connector_filter = warp::any().map({
+        let connector = Arc::clone(&connector);
+        move || connector.clone()
+    });
+    tokio::spawn(exec::expire_idle_sessions(connector.clone()));
     let config_ref = Arc::new(RwLock::new(config));
     let config_filter = warp::any().map({
         let config = Arc::clone(&config_ref);
@@ -46,18 +78,81 @@ async fn main() -> Result<()> {
         move || states.clone()
     });
 
+    let jobs: Jobs = Arc::new(RwLock::new(Vec::new()));
+    let jobs_filter = warp::any().map({
+        let jobs = Arc::clone(&jobs);
+        move || jobs.clone()
+    });
+
+    let failovers: Failovers = Arc::new(FailoverLog::load(&config_path)?);
+    let failovers_filter = warp::any().map({
+        let failovers = Arc::clone(&failovers);
+        move || failovers.clone()
+    });
+
+    let leases: Leases = Arc::new(DatasetLeases::default());
+    let leases_filter = warp::any().map({
+        let leases = Arc::clone(&leases);
+        move || leases.clone()
+    });
+
+    let schedule: Schedule = Arc::new(Scheduler::load(&config_path)?);
+    let schedule_filter = warp::any().map({
+        let schedule = Arc::clone(&schedule);
+        move || schedule.clone()
+    });
+
+    let healths: ServerHealths = Arc::new(RwLock::new(HashMap::new()));
+    let healths_filter = warp::any().map({
+        let healths = Arc::clone(&healths);
+        move || healths.clone()
+    });
+
+    tokio::spawn(probe::run(
+        config_ref.clone(),
+        connector.clone(),
+        healths.clone(),
+    ));
+
+    tokio::spawn(schedule::run(
+        schedule.clone(),
+        config_path.clone(),
+        config_ref.clone(),
+        states.clone(),
+        jobs.clone(),
+        leases.clone(),
+        connector.clone(),
+        healths.clone(),
+    ));
+
+    tokio::spawn(failover::monitor(
+        config_path.clone(),
+        config_ref.clone(),
+        jobs.clone(),
+        failovers.clone(),
+        leases.clone(),
+        connector.clone(),
+        healths,
+    ));
+
     let status = warp::get()
         .and(warp::path("status"))
         .and(warp::path::end())
         .and(config_filter.clone())
-        .then(api::status);
+        .and(connector_filter.clone())
+        .then(api::status)
+        .boxed();
 
     let sync = warp::get()
         .and(warp::path("sync"))
         .and(warp::path::end())
         .and(config_filter.clone())
         .and(states_filter.clone())
-        .then(api::sync::sync_all);
+        .and(jobs_filter.clone())
+        .and(leases_filter.clone())
+        .and(connector_filter.clone())
+        .then(api::sync::sync_all)
+        .boxed();
 
     let sync_one = warp::post()
         .and(warp::path("sync"))
@@ -65,23 +160,201 @@ async fn main() -> Result<()> {
         .and(warp::body::json::<SyncRequest>())
         .and(config_filter.clone())
         .and(states_filter.clone())
-        .then(api::sync::sync);
+        .and(jobs_filter.clone())
+        .and(leases_filter.clone())
+        .and(connector_filter.clone())
+        .then(api::sync::sync)
+        .boxed();
 
     let clean = warp::get()
         .and(warp::path("clean"))
         .and(warp::path::end())
         .and(config_filter.clone())
-        .then(api::clean);
+        .and(leases_filter.clone())
+        .and(connector_filter.clone())
+        .then(api::clean)
+        .boxed();
 
     let switch = warp::post()
         .and(warp::path("switch"))
         .and(warp::path::end())
         .and(warp::body::json::<SwitchRequest>())
+        .and(config_path_filter.clone())
+        .and(config_filter.clone())
+        .and(states_filter.clone())
+        .and(jobs_filter.clone())
+        .and(leases_filter.clone())
+        .and(connector_filter.clone())
+        .then(api::switch)
+        .boxed();
+
+    let readiness = warp::post()
+        .and(warp::path("switch"))
+        .and(warp::path("readiness"))
+        .and(warp::path::end())
+        .and(warp::body::json::<ReadinessRequest>())
+        .and(config_filter.clone())
+        .and(connector_filter.clone())
+        .then(api::readiness::readiness)
+        .boxed();
+
+    let schedule_switch = warp::post()
+        .and(warp::path("switch"))
+        .and(warp::path("schedule"))
+        .and(warp::path::end())
+        .and(warp::body::json::<ScheduleSwitchRequest>())
+        .and(config_filter.clone())
+        .and(schedule_filter.clone())
+        .then(api::schedule::schedule)
+        .boxed();
+
+    let scheduled_switches = warp::get()
+        .and(warp::path("switch"))
+        .and(warp::path("scheduled"))
+        .and(warp::path::end())
+        .and(schedule_filter.clone())
+        .then(api::schedule::scheduled)
+        .boxed();
+
+    let cancel_switch = warp::post()
+        .and(warp::path("switch"))
+        .and(warp::path("cancel"))
+        .and(warp::path::end())
+        .and(warp::body::json::<CancelSwitchRequest>())
+        .and(schedule_filter)
+        .then(api::schedule::cancel)
+        .boxed();
+
+    let snapshot = warp::post()
+        .and(warp::path("snapshot"))
+        .and(warp::path::end())
+        .and(warp::body::json::<SnapshotRequest>())
+        .and(config_path_filter.clone())
+        .and(config_filter.clone())
+        .and(states_filter.clone())
+        .and(jobs_filter.clone())
+        .and(leases_filter.clone())
+        .and(connector_filter.clone())
+        .then(api::snapshot::snapshot)
+        .boxed();
+
+    let pinned = warp::get()
+        .and(warp::path("pinned"))
+        .and(warp::path::end())
+        .and(config_filter.clone())
+        .then(api::snapshot::pinned)
+        .boxed();
+
+    let ownership = warp::get()
+        .and(warp::path("ownership"))
+        .and(warp::path::end())
+        .and(config_filter.clone())
+        .and(connector_filter.clone())
+        .then(api::ownership::ownership)
+        .boxed();
+
+    let adopt = warp::post()
+        .and(warp::path("ownership"))
+        .and(warp::path("adopt"))
+        .and(warp::path::end())
+        .and(warp::body::json::<AdoptRequest>())
+        .and(config_filter.clone())
+        .and(leases_filter.clone())
+        .and(connector_filter.clone())
+        .then(api::ownership::adopt)
+        .boxed();
+
+    let privileges = warp::get()
+        .and(warp::path("privileges"))
+        .and(warp::path::end())
+        .and(config_filter.clone())
+        .and(connector_filter.clone())
+        .then(api::privilege::privileges)
+        .boxed();
+
+    let servers = warp::get()
+        .and(warp::path("servers"))
+        .and(warp::path::end())
+        .and(config_filter.clone())
+        .and(healths_filter)
+        .then(api::server::servers)
+        .boxed();
+
+    let config_versions = warp::get()
+        .and(warp::path("config"))
+        .and(warp::path("versions"))
+        .and(warp::path::end())
+        .and(config_path_filter.clone())
+        .then(api::config_versions::versions)
+        .boxed();
+
+    let restore_config = warp::post()
+        .and(warp::path("config"))
+        .and(warp::path("restore"))
+        .and(warp::path::end())
+        .and(warp::body::json::<RestoreConfigRequest>())
+        .and(config_path_filter.clone())
+        .and(config_filter.clone())
+        .and(leases_filter.clone())
+        .and(connector_filter.clone())
+        .then(api::config_versions::restore)
+        .boxed();
+
+    let unpin = warp::post()
+        .and(warp::path("unpin"))
+        .and(warp::path::end())
+        .and(warp::body::json::<UnpinRequest>())
+        .and(config_path_filter.clone())
+        .and(config_filter.clone())
+        .then(api::snapshot::unpin)
+        .boxed();
+
+    let jobs = warp::get()
+        .and(warp::path("jobs"))
+        .and(warp::path::end())
+        .and(jobs_filter)
+        .then(api::jobs::jobs)
+        .boxed();
+
+    let failovers = warp::get()
+        .and(warp::path("failovers"))
+        .and(warp::path::end())
+        .and(failovers_filter.clone())
+        .then(api::failover::failovers)
+        .boxed();
+
+    let acknowledge_failover = warp::post()
+        .and(warp::path("failover"))
+        .and(warp::path("acknowledge"))
+        .and(warp::path::end())
+        .and(warp::body::json::<AcknowledgeFailoverRequest>())
         .and(config_path_filter)
         .and(config_filter)
-        .then(api::switch);
+        .and(failovers_filter)
+        .then(api::failover::acknowledge)
+        .boxed();
 
-    let routes = status.or(sync).or(clean).or(switch).or(sync_one);
+    let routes = status
+        .or(sync)
+        .or(clean)
+        .or(switch)
+        .or(readiness)
+        .or(schedule_switch)
+        .or(scheduled_switches)
+        .or(cancel_switch)
+        .or(sync_one)
+        .or(snapshot)
+        .or(pinned)
+        .or(unpin)
+        .or(ownership)
+        .or(adopt)
+        .or(privileges)
+        .or(servers)
+        .or(config_versions)
+        .or(restore_config)
+        .or(jobs)
+        .or(failovers)
+        .or(acknowledge_failover);
 
     warp::serve(routes).run(([0, 0, 0, 0], 3030)).await;
 
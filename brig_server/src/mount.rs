@@ -0,0 +1,51 @@
+use brig_common::api::api::ErrorCode;
+
+use crate::{
+    config::{
+        dataset::Dataset,
+        mount::{MountPolicy, MountRole},
+        server::Server,
+    },
+    exec::ExecutorRef,
+    utils,
+};
+
+/// Brings the mount state of `dataset` on `server` in line with `policy` for
+/// `role`: the owner is mounted, a replica is unmounted, but only once nothing
+/// is using its mountpoint.
+pub async fn enforce(
+    session: &ExecutorRef,
+    server: &Server,
+    dataset: &Dataset,
+    policy: &MountPolicy,
+    role: MountRole,
+) -> Result<(), ErrorCode> {
+    let target = format!("{}/{}", &server.pool, &dataset.name);
+    utils::set_property(
+        session,
+        server,
+        &dataset.name,
+        "canmount",
+        policy.canmount(role).as_str(),
+    )
+    .await?;
+
+    let mounted = utils::get_property(session, &target, "mounted").await? == "yes";
+    match role {
+        MountRole::Owner if !mounted => {
+            utils::set_mounted(session, server, &dataset.name, true).await
+        }
+        MountRole::Replica if mounted => {
+            let mountpoint = utils::get_property(session, &target, "mountpoint").await?;
+            if utils::mountpoint_in_use(session, server, &mountpoint).await? {
+                return Err(ErrorCode::MountpointInUse {
+                    server: server.name.clone(),
+                    dataset: dataset.name.clone(),
+                    mountpoint,
+                });
+            }
+            utils::set_mounted(session, server, &dataset.name, false).await
+        }
+        _ => Ok(()),
+    }
+}
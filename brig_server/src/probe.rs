@@ -0,0 +1,140 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use brig_common::{agent::Operation, api::server::ServerHealth};
+use chrono::Utc;
+use tokio::task::JoinSet;
+
+use crate::{ConfigRef, ConnectorRef, ServerHealths, config::server::Server};
+
+/// Connects to `server` and reads how long a trivial command takes, its zfs
+/// version and whether its pool is imported
+pub async fn probe(server: &Server, connector: &ConnectorRef) -> ServerHealth {
+    let mut health = ServerHealth {
+        server: server.name.clone(),
+        reachable: false,
+        latency_ms: None,
+        zfs_version: None,
+        pool_imported: false,
+        pool_health: None,
+        error: None,
+        checked_at: Utc::now(),
+        healthy: false,
+    };
+    let session = match connector.connect(server).await {
+        Ok(session) => session,
+        Err(e) => {
+            health.error = Some(e);
+            return health;
+        }
+    };
+
+    let started = Instant::now();
+    let round_trip = session
+        .command(Operation::UserId)
+        .short()
+        .checked_output()
+        .await;
+    if let Err(e) = round_trip {
+        health.error = Some(e);
+        return health;
+    }
+    health.reachable = true;
+    health.latency_ms = Some(started.elapsed().as_millis() as u64);
+
+    // zfs older than 0.8 has no `version`, which only costs the version
+    health.zfs_version = session
+        .command(Operation::ZfsVersion)
+        .short()
+        .checked_output()
+        .await
+        .ok()
+        .and_then(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .next()
+                .map(|line| line.trim().to_owned())
+        });
+
+    let pool = session
+        .command(Operation::PoolProperty {
+            pool: server.pool.clone(),
+            property: "health".to_owned(),
+        })
+        .short()
+        .checked_output()
+        .await;
+    match pool {
+        Ok(output) => {
+            health.pool_imported = true;
+            health.pool_health = Some(String::from_utf8_lossy(&output.stdout).trim().to_owned());
+        }
+        Err(e) => health.error = Some(e),
+    }
+    // a degraded pool has lost redundancy, so it isn't trusted with a switch
+    // or failover either
+    health.healthy = health.pool_health.as_deref() == Some("ONLINE");
+    health
+}
+
+/// Whether the last probe of `server` found it unhealthy. Servers that
+/// haven't been probed yet are given the benefit of the doubt.
+pub async fn is_unhealthy(healths: &ServerHealths, server: &str) -> bool {
+    healths
+        .read()
+        .await
+        .get(server)
+        .is_some_and(|health| !health.healthy)
+}
+
+/// Probes every server in the config every `probe.interval` seconds, keeping
+/// the latest result of each in `healths`
+pub async fn run(config_arc: ConfigRef, connector: ConnectorRef, healths: ServerHealths) {
+    let mut last_probes: HashMap<String, Instant> = HashMap::new();
+    loop {
+        let config = { config_arc.read().await.clone() };
+        let interval = Duration::from_secs(config.probe.interval);
+
+        let mut probes = JoinSet::new();
+        for server in &config.servers {
+            if last_probes
+                .get(&server.name)
+                .is_some_and(|last| last.elapsed() < interval)
+            {
+                continue;
+            }
+            last_probes.insert(server.name.clone(), Instant::now());
+            let server = server.clone();
+            let connector = connector.clone();
+            probes.spawn(async move { probe(&server, &connector).await });
+        }
+
+        while let Some(result) = probes.join_next().await {
+            let Ok(health) = result else {
+                continue;
+            };
+            let mut healths = healths.write().await;
+            let was_healthy = healths.get(&health.server).map(|last| last.healthy);
+            if health.healthy && was_healthy == Some(false) {
+                println!("server {} is healthy again", &health.server);
+            }
+            if !health.healthy && was_healthy != Some(false) {
+                println!(
+                    "server {} is unhealthy, pool health {:?}: {:?}",
+                    &health.server, &health.pool_health, &health.error
+                );
+            }
+            healths.insert(health.server.clone(), health);
+        }
+
+        // servers removed from the config aren't reported any more
+        healths
+            .write()
+            .await
+            .retain(|name, _| config.servers.iter().any(|server| &server.name == name));
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
@@ -0,0 +1,275 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use brig_common::api::{api::ErrorCode, switch::SwitchRequest};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::{
+    ConfigRef, ConnectorRef, Jobs, Leases, Schedule, ServerHealths, SyncStates, api::switch,
+    config::backup, job, probe,
+};
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum ScheduleStatus {
+    Pending,
+    Running,
+    /// the switch succeeded
+    Finished,
+    /// the switch ran but failed, `error` says why
+    Failed,
+    Cancelled,
+    /// brig couldn't start the switch before its deadline
+    Expired,
+    /// brig stopped while the switch was running
+    Interrupted,
+}
+
+/// A switch waiting for (or done with) its maintenance window
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ScheduledSwitch {
+    pub id: u64,
+    pub request: SwitchRequest,
+    pub at: DateTime<Utc>,
+    pub deadline: DateTime<Utc>,
+    pub status: ScheduleStatus,
+    /// the job in `/jobs` that ran the switch. Jobs aren't kept across
+    /// restarts, so this is cleared on load and the outcome is in `status`
+    /// and `error`.
+    pub job: Option<u64>,
+    pub error: Option<ErrorCode>,
+}
+
+/// Scheduled switches, kept in a file next to the config so they survive
+/// restarts
+pub struct Scheduler {
+    path: PathBuf,
+    switches: RwLock<Vec<ScheduledSwitch>>,
+}
+
+impl Scheduler {
+    pub fn load(config_path: &Path) -> Result<Self> {
+        let path = backup::with_suffix(config_path, ".schedule");
+
+        let mut switches: Vec<ScheduledSwitch> = if path.exists() {
+            let contents = std::fs::read_to_string(&path)
+                .with_context(|| format!("unable to read schedule file {}", path.display()))?;
+            serde_json::from_str(&contents)
+                .with_context(|| format!("invalid schedule file {}", path.display()))?
+        } else {
+            vec![]
+        };
+        for switch in switches.iter_mut() {
+            if switch.status == ScheduleStatus::Running {
+                switch.status = ScheduleStatus::Interrupted;
+            }
+            switch.job = None;
+        }
+        Ok(Self {
+            path,
+            switches: RwLock::new(switches),
+        })
+    }
+
+    fn save(&self, switches: &[ScheduledSwitch]) -> Result<(), ErrorCode> {
+        let json_str =
+            serde_json::to_string_pretty(switches).map_err(|_| ErrorCode::ConfigIsInvalidJson)?;
+        backup::write_atomic(&self.path, &json_str)
+    }
+
+    pub async fn list(&self) -> Vec<ScheduledSwitch> {
+        self.switches.read().await.clone()
+    }
+
+    pub async fn add(
+        &self,
+        request: SwitchRequest,
+        at: DateTime<Utc>,
+        deadline: DateTime<Utc>,
+    ) -> Result<ScheduledSwitch, ErrorCode> {
+        if deadline <= at || deadline <= Utc::now() {
+            return Err(ErrorCode::InvalidSchedule {
+                msg: "the deadline must be in the future and after the start".to_owned(),
+            });
+        }
+        let mut switches = self.switches.write().await;
+        let switch = ScheduledSwitch {
+            id: switches.iter().map(|s| s.id + 1).max().unwrap_or(1),
+            request,
+            at,
+            deadline,
+            status: ScheduleStatus::Pending,
+            job: None,
+            error: None,
+        };
+        switches.push(switch.clone());
+        if let Err(e) = self.save(&switches) {
+            switches.pop();
+            return Err(e);
+        }
+        Ok(switch)
+    }
+
+    pub async fn cancel(&self, id: u64) -> Result<ScheduledSwitch, ErrorCode> {
+        self.update(id, |switch| {
+            if switch.status != ScheduleStatus::Pending {
+                return Err(ErrorCode::ScheduledSwitchNotPending { id });
+            }
+            switch.status = ScheduleStatus::Cancelled;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Applies `f` to the switch with `id` and persists the result
+    async fn update(
+        &self,
+        id: u64,
+        f: impl FnOnce(&mut ScheduledSwitch) -> Result<(), ErrorCode>,
+    ) -> Result<ScheduledSwitch, ErrorCode> {
+        let mut switches = self.switches.write().await;
+        let switch = switches
+            .iter_mut()
+            .find(|switch| switch.id == id)
+            .ok_or(ErrorCode::ScheduledSwitchNotFound { id })?;
+        f(switch)?;
+        let switch = switch.clone();
+        self.save(&switches)?;
+        Ok(switch)
+    }
+
+    async fn due(&self) -> Vec<ScheduledSwitch> {
+        let now = Utc::now();
+        self.switches
+            .read()
+            .await
+            .iter()
+            .filter(|switch| switch.status == ScheduleStatus::Pending && switch.at <= now)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Starts scheduled switches once they're due, or marks them expired if
+/// brig wasn't able to start them before their deadline. A switch to a
+/// server the last probe found unhealthy waits for it to recover.
+#[allow(clippy::too_many_arguments)]
+pub async fn run(
+    schedule: Schedule,
+    config_path: Arc<PathBuf>,
+    config_arc: ConfigRef,
+    states: SyncStates,
+    jobs: Jobs,
+    leases: Leases,
+    connector: ConnectorRef,
+    healths: ServerHealths,
+) {
+    let mut waiting: HashSet<u64> = HashSet::new();
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        for due in schedule.due().await {
+            if Utc::now() <= due.deadline
+                && probe::is_unhealthy(&healths, &due.request.new_server).await
+            {
+                if waiting.insert(due.id) {
+                    println!(
+                        "scheduled switch {} is waiting for {} to become healthy",
+                        due.id, &due.request.new_server
+                    );
+                }
+                continue;
+            }
+            waiting.remove(&due.id);
+
+            if Utc::now() > due.deadline {
+                let e = ErrorCode::SwitchDeadlinePassed {
+                    dataset: due.request.dataset.clone(),
+                };
+                println!("scheduled switch {} expired: {:?}", due.id, &e);
+                let result = schedule
+                    .update(due.id, |switch| {
+                        if switch.status != ScheduleStatus::Pending {
+                            return Err(ErrorCode::ScheduledSwitchNotPending { id: due.id });
+                        }
+                        switch.status = ScheduleStatus::Expired;
+                        switch.error = Some(e);
+                        Ok(())
+                    })
+                    .await;
+                if let Err(e) = result {
+                    println!("failed to update scheduled switch {}: {:?}", due.id, &e);
+                }
+                continue;
+            }
+
+            // it may have been cancelled since it was found due
+            let result = schedule
+                .update(due.id, |switch| {
+                    if switch.status != ScheduleStatus::Pending {
+                        return Err(ErrorCode::ScheduledSwitchNotPending { id: due.id });
+                    }
+                    switch.status = ScheduleStatus::Running;
+                    Ok(())
+                })
+                .await;
+            if let Err(e) = result {
+                println!("failed to start scheduled switch {}: {:?}", due.id, &e);
+                continue;
+            }
+
+            let job = job::start_job(&jobs, "scheduled switch", &due.request.dataset).await;
+            let job_id = job.read().await.id;
+            let result = schedule
+                .update(due.id, |switch| {
+                    switch.job = Some(job_id);
+                    Ok(())
+                })
+                .await;
+            if let Err(e) = result {
+                println!("failed to update scheduled switch {}: {:?}", due.id, &e);
+            }
+
+            tokio::spawn({
+                let schedule = schedule.clone();
+                let config_path = config_path.clone();
+                let config_arc = config_arc.clone();
+                let states = states.clone();
+                let leases = leases.clone();
+                let connector = connector.clone();
+                async move {
+                    let result = switch::scheduled_switch(
+                        &due,
+                        &config_path,
+                        &config_arc,
+                        &states,
+                        &leases,
+                        &connector,
+                        job.clone(),
+                    )
+                    .await;
+                    job::finish_job(&job, result.clone()).await;
+                    let update = schedule
+                        .update(due.id, |switch| {
+                            switch.status = match result {
+                                Ok(()) => ScheduleStatus::Finished,
+                                Err(_) => ScheduleStatus::Failed,
+                            };
+                            switch.error = result.err();
+                            Ok(())
+                        })
+                        .await;
+                    if let Err(e) = update {
+                        println!("failed to update scheduled switch {}: {:?}", due.id, &e);
+                    }
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use std::path::Path;
+
+use brig_common::api::{
+    api::ErrorCode,
+    switch::{StepOutcome, SwitchReport, SwitchStep},
+};
+
+use crate::{
+    ConfigRef, ConnectorRef, JobRef,
+    config::{
+        dataset::Dataset,
+        mount::{MountPolicy, MountRole},
+        server::Server,
+    },
+    job, mount, utils,
+};
+
+/// How to undo a step of a switch that completed
+pub enum Compensation {
+    /// the step changed nothing that needs restoring
+    None,
+    SetReadonly {
+        server: Server,
+        readonly: bool,
+    },
+    SetOwner {
+        server: String,
+    },
+    EnforceMount {
+        server: Server,
+        dataset: Box<Dataset>,
+        policy: MountPolicy,
+        role: MountRole,
+    },
+}
+
+/// Records the steps of a switch as they run so that, if a later one fails,
+/// the completed ones can be undone in reverse order.
+pub struct SwitchTransaction {
+    dataset: String,
+    report: SwitchReport,
+    compensations: Vec<(usize, Compensation)>,
+    connector: ConnectorRef,
+    job: Option<JobRef>,
+}
+
+impl SwitchTransaction {
+    pub fn new(
+        dataset: &Dataset,
+        old_server: &Server,
+        new_server: &Server,
+        connector: &ConnectorRef,
+        job: Option<JobRef>,
+    ) -> Self {
+        Self {
+            dataset: dataset.name.clone(),
+            report: SwitchReport {
+                dataset: dataset.name.clone(),
+                old_server: old_server.name.clone(),
+                new_server: new_server.name.clone(),
+                steps: vec![],
+                error: None,
+            },
+            compensations: vec![],
+            connector: connector.clone(),
+            job,
+        }
+    }
+
+    /// How the switch connects to servers, also used to undo its steps
+    pub fn connector(&self) -> &ConnectorRef {
+        &self.connector
+    }
+
+    async fn log(&self, name: &str, outcome: &StepOutcome) {
+        if let Some(job) = &self.job {
+            job::log_step(job, format!("{:?}: {}", outcome, name)).await;
+        } else {
+            println!("switch {}: {:?}: {}", &self.dataset, outcome, name);
+        }
+    }
+
+    async fn record(
+        &mut self,
+        name: String,
+        outcome: StepOutcome,
+        output: Option<String>,
+    ) -> usize {
+        self.log(&name, &outcome).await;
+        self.report.steps.push(SwitchStep {
+            name,
+            outcome,
+            output,
+        });
+        self.report.steps.len() - 1
+    }
+
+    /// Records the outcome of a step, remembering how to undo it if it succeeded
+    pub async fn step<T>(
+        &mut self,
+        name: String,
+        result: Result<T, ErrorCode>,
+        compensation: Compensation,
+    ) -> Result<T, ErrorCode> {
+        match result {
+            Ok(value) => {
+                let index = self.record(name, StepOutcome::Done, None).await;
+                self.compensations.push((index, compensation));
+                Ok(value)
+            }
+            Err(e) => {
+                self.record(name, StepOutcome::Failed, None).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Records a hook that ran, along with what it printed. Hooks can't be undone.
+    pub async fn hook_step(
+        &mut self,
+        name: String,
+        result: Result<(), ErrorCode>,
+        output: Option<String>,
+    ) -> Result<(), ErrorCode> {
+        match result {
+            Ok(()) => {
+                self.record(name, StepOutcome::Done, output).await;
+                Ok(())
+            }
+            Err(e) => {
+                self.record(name, StepOutcome::Failed, output).await;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn commit(self) -> SwitchReport {
+        self.report
+    }
+
+    /// Undoes every completed step, newest first
+    pub async fn rollback(
+        mut self,
+        error: ErrorCode,
+        config_path: &Path,
+        config_arc: &ConfigRef,
+    ) -> SwitchReport {
+        while let Some((index, compensation)) = self.compensations.pop() {
+            let result = match compensation {
+                Compensation::None => continue,
+                Compensation::SetReadonly { server, readonly } => {
+                    match self.connector.connect(&server).await {
+                        Ok(session) => {
+                            utils::set_readonly(&session, &server, &self.dataset, readonly).await
+                        }
+                        Err(e) => Err(e),
+                    }
+                }
+                Compensation::EnforceMount {
+                    server,
+                    dataset,
+                    policy,
+                    role,
+                } => match self.connector.connect(&server).await {
+                    Ok(session) => mount::enforce(&session, &server, &dataset, &policy, role).await,
+                    Err(e) => Err(e),
+                },
+                Compensation::SetOwner { server } => {
+                    let mut config = config_arc.write().await;
+                    match config
+                        .datasets
+                        .iter_mut()
+                        .find(|ds: &&mut Dataset| ds.name == self.dataset)
+                    {
+                        Some(dataset) => {
+                            dataset.server = server;
+                            config.save(config_path)
+                        }
+                        None => Err(ErrorCode::DatasetNotFoundInConfig {
+                            dataset: self.dataset.clone(),
+                        }),
+                    }
+                }
+            };
+
+            let outcome = match result {
+                Ok(()) => StepOutcome::RolledBack,
+                Err(e) => {
+                    println!("failed to roll back switch of {}: {:?}", &self.dataset, &e);
+                    StepOutcome::RollbackFailed
+                }
+            };
+            self.log(&self.report.steps[index].name, &outcome).await;
+            self.report.steps[index].outcome = outcome;
+        }
+        self.report.error = Some(error);
+        self.report
+    }
+}
@@ -7,4 +7,7 @@ pub struct SyncState {
     pub dst: String,
     pub total_bytes: u64,
     pub sent_bytes: u64,
+    /// the job a background sync is recorded as, which has its outcome once
+    /// the sync is no longer listed
+    pub job: Option<u64>,
 }
@@ -1,270 +1,443 @@
-use brig_common::api::api::ErrorCode;
-use chrono::Local;
-use openssh::{KnownHosts, Session, Stdio};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::{io, time::Duration};
 
-use crate::{
-    SyncStateRef, config::{dataset::Dataset, server::Server},
+use brig_common::{
+    agent::Operation,
+    api::{api::ErrorCode, switch::DiffSummary},
+};
+use chrono::Utc;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::timeout,
 };
 
-pub async fn create_ssh_session(user: &str, address: &str) -> Result<Session, ErrorCode> {
-    Session::connect(format!("{}@{}", user, address), KnownHosts::Strict)
-        .await
-        .map_err(|_| ErrorCode::SshSessionFail {
-            user: user.to_owned(),
-            ip: address.to_owned(),
-        })
-}
+use crate::{
+    SyncStateRef,
+    config::{dataset::Dataset, naming::SnapshotNaming, server::Server},
+    exec::{self, ExecutorRef},
+    zfs::Snapshot,
+};
 
 pub async fn set_readonly(
-    session: &Session,
+    exec: &ExecutorRef,
     server: &Server,
     dataset: &str,
     is_on: bool,
 ) -> Result<(), ErrorCode> {
-    let err = ErrorCode::ReadOnlyFail {
-        user: server.user.clone(),
-        ip: server.address.clone(),
-    };
-    session
-        .command("sudo")
-        .arg("zfs")
-        .arg("set")
-        .arg(if is_on { "readonly=on" } else { "readonly=off" })
-        .arg(format!(
-            "{pool}/{dataset}",
-            pool = &server.pool,
-            dataset = dataset
-        ))
-        .status()
-        .await
-        .map_err(|_| err)?;
+    set_property(
+        exec,
+        server,
+        dataset,
+        "readonly",
+        if is_on { "on" } else { "off" },
+    )
+    .await
+}
+
+/// Sets a zfs property on `pool/dataset`
+pub async fn set_property(
+    exec: &ExecutorRef,
+    server: &Server,
+    dataset: &str,
+    property: &str,
+    value: &str,
+) -> Result<(), ErrorCode> {
+    exec.command(Operation::SetProperty {
+        filesystem: format!("{}/{}", &server.pool, dataset),
+        property: property.to_owned(),
+        value: value.to_owned(),
+    })
+    .short()
+    .checked_output()
+    .await?;
     Ok(())
 }
 
-pub async fn list_snapshots(
-    session: &Session,
-    pool: &str,
+/// Mounts `pool/dataset` at its `mountpoint`, or unmounts it
+pub async fn set_mounted(
+    exec: &ExecutorRef,
+    server: &Server,
     dataset: &str,
-) -> Result<Vec<String>, ErrorCode> {
-    let output = session
-        .command("zfs")
-        .args(["list", "-t", "snapshot", "-o", "name", "-S", "creation"])
-        .arg(format!("{}/{}", pool, dataset))
+    mounted: bool,
+) -> Result<(), ErrorCode> {
+    let output = exec
+        .command(Operation::Mount {
+            filesystem: format!("{}/{}", &server.pool, dataset),
+            mounted,
+        })
+        .short()
         .output()
         .await
-        .map_err(|_| ErrorCode::ZfsCommandError {
-            msg: format!("failed to list snapshots: {}/{}", pool, dataset),
+        .map_err(|_| ErrorCode::MountFail {
+            server: server.name.clone(),
+            dataset: dataset.to_owned(),
+            msg: "unable to run zfs mount".to_owned(),
         })?;
 
-    if !&output.stderr.is_empty() {
-        Err(ErrorCode::ZfsCommandError {
-            msg: format!("{}", String::from_utf8_lossy(&output.stderr)),
+    if !output.success() {
+        return Err(ErrorCode::MountFail {
+            server: server.name.clone(),
+            dataset: dataset.to_owned(),
+            msg: String::from_utf8_lossy(&output.stderr).to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Whether any process has a file open under `mountpoint`. `fuser` exits
+/// with 0 if it found one and 1 if it didn't.
+pub async fn mountpoint_in_use(
+    exec: &ExecutorRef,
+    server: &Server,
+    mountpoint: &str,
+) -> Result<bool, ErrorCode> {
+    let output = exec
+        .command(Operation::MountpointInUse {
+            mountpoint: mountpoint.to_owned(),
         })
-    } else {
-        Ok(String::from_utf8_lossy(&output.stdout)
-            .lines()
-            .skip(1)
-            .map(|s| s.to_string())
-            .collect())
+        .short()
+        .output()
+        .await
+        .map_err(|_| ErrorCode::MountFail {
+            server: server.name.clone(),
+            dataset: mountpoint.to_owned(),
+            msg: "unable to run fuser".to_owned(),
+        })?;
+    match output.status {
+        Some(0) => Ok(true),
+        Some(1) if output.stdout.is_empty() => Ok(false),
+        _ => Err(ErrorCode::MountFail {
+            server: server.name.clone(),
+            dataset: mountpoint.to_owned(),
+            msg: String::from_utf8_lossy(&output.stderr).to_string(),
+        }),
     }
 }
 
+/// Runs `command` through `sh -c`, returning its exit status and combined
+/// stdout and stderr. A command that runs longer than `timeouts.hook` fails.
+pub async fn run_shell(
+    exec: &ExecutorRef,
+    server: &Server,
+    command: &str,
+) -> Result<(Option<i32>, String), ErrorCode> {
+    let seconds = exec.timeouts().hook;
+    let output = exec
+        .command(Operation::Hook {
+            command: command.to_owned(),
+        })
+        .timeout(Duration::from_secs(seconds))
+        .output()
+        .await
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::TimedOut => ErrorCode::HookTimedOut {
+                server: server.name.clone(),
+                command: command.to_owned(),
+                seconds,
+            },
+            _ => ErrorCode::HookCommandError {
+                server: server.name.clone(),
+                command: command.to_owned(),
+            },
+        })?;
+    let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    Ok((output.status, text))
+}
+
+pub async fn get_readonly(
+    exec: &ExecutorRef,
+    server: &Server,
+    dataset: &str,
+) -> Result<bool, ErrorCode> {
+    let value = get_property(
+        exec,
+        &format!("{pool}/{dataset}", pool = &server.pool, dataset = dataset),
+        "readonly",
+    )
+    .await?;
+    Ok(value == "on")
+}
+
+/// The newest snapshot of `src` that `dst` has received, found by guid so a
+/// snapshot that was recreated under the same name doesn't count
 pub async fn find_latest_common_snapshot(
     dataset: &str,
-    src_snapshots: &Vec<String>,
-    dst_snapshots: &Vec<String>,
+    src_snapshots: &[Snapshot],
+    dst_snapshots: &[Snapshot],
+) -> Result<Snapshot, ErrorCode> {
+    src_snapshots
+        .iter()
+        .find(|src| dst_snapshots.iter().any(|dst| dst.guid == src.guid))
+        .cloned()
+        .ok_or(ErrorCode::NoCommonSnapshot {
+            dataset: dataset.to_owned(),
+        })
+}
+
+pub async fn create_snapshot(
+    exec: &ExecutorRef,
+    pool: &str,
+    dataset: &str,
+    naming: &SnapshotNaming,
+    label: Option<&str>,
 ) -> Result<String, ErrorCode> {
-    let mut common_snapshot = None;
-    for src_snapshot in src_snapshots {
-        if dst_snapshots.contains(&src_snapshot.split_once('@').unwrap().1.to_string()) {
-            common_snapshot = Some(src_snapshot.to_owned());
-            break;
+    let now = Utc::now();
+    let mut sequence = 0;
+    loop {
+        let snapshot = format!(
+            "{pool}/{dataset}@{name}",
+            pool = &pool,
+            dataset = &dataset,
+            name = naming.name(now, label, sequence)
+        );
+        let command = exec
+            .command(Operation::Snapshot {
+                snapshot: snapshot.clone(),
+            })
+            .short();
+        let line = command.line();
+        let output = command
+            .output()
+            .await
+            .map_err(|e| exec::not_run(exec.server(), &line, &e))?;
+
+        if output.success() {
+            return Ok(snapshot);
         }
+        // another snapshot was taken within the same second
+        if String::from_utf8_lossy(&output.stderr).contains("exists") && sequence < 100 {
+            sequence += 1;
+            continue;
+        }
+        return Err(exec::failed(exec.server(), &line, &output));
     }
-    common_snapshot.ok_or(ErrorCode::NoCommonSnapshot {
-        dataset: dataset.to_owned(),
+}
+
+pub async fn destroy_snapshot(exec: &ExecutorRef, snapshot: &str) -> Result<(), ErrorCode> {
+    exec.command(Operation::DestroySnapshot {
+        snapshot: snapshot.to_owned(),
     })
+    .long()
+    .checked_output()
+    .await?;
+    Ok(())
 }
 
-pub async fn create_snapshot(
-    session: &Session,
-    pool: &str,
-    dataset: &str,
+pub async fn get_property(
+    exec: &ExecutorRef,
+    target: &str,
+    property: &str,
 ) -> Result<String, ErrorCode> {
-    let timestamp = Local::now().format("%Y%m%d%H%M%S").to_string();
-    let snapshot = format!(
-        "{pool}/{dataset}@brig-{snapshot}",
-        pool = &pool,
-        dataset = &dataset,
-        snapshot = &timestamp
-    );
-    session
-        .command("zfs")
-        .arg("snapshot")
-        .arg(&snapshot)
-        .status()
-        .await
-        .map_err(|_| ErrorCode::ZfsCommandError {
-            msg: format!("failed to take snapshot {}", &snapshot),
-        })?;
+    let output = exec
+        .command(Operation::GetProperty {
+            target: target.to_owned(),
+            property: property.to_owned(),
+        })
+        .short()
+        .checked_output()
+        .await?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
 
-    Ok(snapshot)
+/// Percentage of the pool's space that is in use
+pub async fn get_pool_capacity(exec: &ExecutorRef, pool: &str) -> Result<u64, ErrorCode> {
+    get_pool_number(exec, pool, "capacity").await
 }
 
-pub async fn estimate_send_size(session: &Session, from: &str, to: &str) -> Result<u64, ErrorCode> {
-    let output = session
-        .command("zfs")
-        .arg("send")
-        .arg("-n")
-        .arg("-P")
-        .arg("-i")
-        .arg(&from)
-        .arg(&to)
-        .output()
-        .await
-        .map_err(|_| ErrorCode::ZfsCommandError {
-            msg: format!("failed to estimate size from {} to {}", &from, &to),
-        })?;
+/// A numeric property of `pool` such as `size` or `allocated`, in bytes
+/// where it's a size
+pub async fn get_pool_number(
+    exec: &ExecutorRef,
+    pool: &str,
+    property: &str,
+) -> Result<u64, ErrorCode> {
+    let command = exec
+        .command(Operation::PoolProperty {
+            pool: pool.to_owned(),
+            property: property.to_owned(),
+        })
+        .short();
+    let line = command.line();
+    let output = command.checked_output().await?;
 
-    if !&output.stderr.is_empty() {
-        return Err(ErrorCode::ZfsCommandError {
-            msg: String::from_utf8_lossy(&output.stderr).to_string(),
-        });
-    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let value = stdout.trim().trim_end_matches('%');
+    value.parse().map_err(|_| ErrorCode::ZfsOutputParseError {
+        command: line,
+        line: value.to_owned(),
+        msg: format!("{} of pool {} is not a number", property, pool),
+    })
+}
+
+pub async fn estimate_send_size(
+    exec: &ExecutorRef,
+    from: &str,
+    to: &str,
+) -> Result<u64, ErrorCode> {
+    let command = exec
+        .command(Operation::EstimateSend {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        })
+        .short();
+    let line = command.line();
+    let output = command.checked_output().await?;
 
     let stdout = String::from_utf8_lossy(&output.stdout);
-    let size_line_error = ErrorCode::ZfsCommandError {
-        msg: format!("couldn't find size for: {}", &stdout),
+    let parse_error = |output_line: &str, msg: &str| ErrorCode::ZfsOutputParseError {
+        command: line.clone(),
+        line: output_line.to_owned(),
+        msg: msg.to_owned(),
     };
     let size_line = stdout
         .lines()
         .find(|line| line.starts_with("size"))
-        .ok_or(size_line_error.clone())?;
+        .ok_or_else(|| parse_error(&stdout, "no size line"))?;
 
-    let total_bytes_str = size_line.split_whitespace().nth(1).ok_or(size_line_error)?;
+    let total_bytes_str = size_line
+        .split_whitespace()
+        .nth(1)
+        .ok_or_else(|| parse_error(size_line, "no size"))?;
 
     total_bytes_str
         .parse()
-        .map_err(|_| ErrorCode::ZfsCommandError {
-            msg: format!(
-                "size is not a number!\ntried to parse {}\nfrom: {}",
-                &total_bytes_str, &stdout
-            ),
-        })
+        .map_err(|_| parse_error(size_line, "size is not a number"))
 }
 
+/// Pipes `zfs send -i from to` into `zfs recv` on `dst`, returning how many
+/// bytes were sent
 pub async fn send_bytes(
-    src_session: &Session,
-    dst_session: &Session,
+    src_exec: &ExecutorRef,
+    dst_exec: &ExecutorRef,
     from: &str,
     to: &str,
     dst: &Server,
     dataset: &Dataset,
     state: &SyncStateRef,
-) -> Result<(), ErrorCode> {
-    let mut zfs_send = src_session
-        .command("zfs")
-        .arg("send")
-        .arg("-i")
-        .arg(&from)
-        .arg(&to)
-        .stdout(Stdio::piped())
+) -> Result<u64, ErrorCode> {
+    let send_command = src_exec
+        .command(Operation::Send {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        })
+        .piped_stdout();
+    let send_line = send_command.line();
+    let mut zfs_send = send_command
         .spawn()
         .await
-        .map_err(|_| ErrorCode::ZfsCommandError {
-            msg: format!("failed to spawn zfs send! from {} to {}", &from, &to),
-        })?;
+        .map_err(|e| exec::not_run(src_exec.server(), &send_line, &e))?;
 
-    let mut zfs_recv = dst_session
-        .command("zfs")
-        .arg("recv")
-        .arg("-F")
-        .arg(format!("{}/{}", &dst.pool, &dataset.name))
-        .stdin(Stdio::piped())
+    let recv_command = dst_exec
+        .command(Operation::Receive {
+            filesystem: format!("{}/{}", &dst.pool, &dataset.name),
+        })
+        .piped_stdin();
+    let recv_line = recv_command.line();
+    let mut zfs_recv = recv_command
         .spawn()
         .await
-        .map_err(|_| ErrorCode::ZfsCommandError {
-            msg: format!("failed to spawn zfs recv! from {} to {}", &from, &to),
-        })?;
+        .map_err(|e| exec::not_run(dst_exec.server(), &recv_line, &e))?;
 
     let mut send_output = zfs_send
-        .stdout()
-        .take()
+        .take_stdout()
         .ok_or(ErrorCode::FailedToTakeStdout {
             to: to.to_string(),
             from: from.to_string(),
         })?;
-    let mut recv_input = zfs_recv
-        .stdin()
-        .take()
-        .ok_or(ErrorCode::FailedToTakeStdin {
-            to: to.to_string(),
-            from: from.to_string(),
-        })?;
+    let mut recv_input = zfs_recv.take_stdin().ok_or(ErrorCode::FailedToTakeStdin {
+        to: to.to_string(),
+        from: from.to_string(),
+    })?;
+
+    // fails the transfer if a read or write makes no progress for this long,
+    // e.g. because `zfs recv` stopped reading or the connection hung
+    let seconds = src_exec.timeouts().transfer_inactivity;
+    let inactivity = Duration::from_secs(seconds);
+    let stalled = || ErrorCode::TransferStalled {
+        from: from.to_string(),
+        to: to.to_string(),
+        seconds,
+    };
 
     let mut total_bytes_sent: u64 = 0;
     let mut buffer = [0u8; 65536]; // 64 KiB buffer
-    loop {
-        let n = send_output
-            .read(&mut buffer)
-            .await
-            .map_err(|_| ErrorCode::FailedToReadSendOutputToBuffer)?;
-        if n == 0 {
-            break;
+    let streamed: Result<(), ErrorCode> = async {
+        loop {
+            let n = timeout(inactivity, send_output.read(&mut buffer))
+                .await
+                .map_err(|_| stalled())?
+                .map_err(|_| ErrorCode::FailedToReadSendOutputToBuffer)?;
+            if n == 0 {
+                break;
+            }
+            timeout(inactivity, recv_input.write_all(&buffer[..n]))
+                .await
+                .map_err(|_| stalled())?
+                .map_err(|_| ErrorCode::FailedToWriteBufferToRecvInput)?;
+            total_bytes_sent += n as u64;
+            {
+                let mut state = state.write().await;
+                state.sent_bytes = total_bytes_sent;
+            }
         }
-        recv_input
-            .write_all(&buffer[..n])
+        timeout(inactivity, recv_input.shutdown())
             .await
-            .map_err(|_| ErrorCode::FailedToWriteBufferToRecvInput)?;
-        total_bytes_sent += n as u64;
-        {
-            let mut state = state.write().await;
-            state.sent_bytes = total_bytes_sent;
-        }
+            .map_err(|_| stalled())?
+            .map_err(|_| ErrorCode::FailedToShutdownOutputStream)
     }
-    recv_input
-        .shutdown()
-        .await
-        .map_err(|_| ErrorCode::FailedToShutdownOutputStream)?;
+    .await;
+    if matches!(streamed, Err(ErrorCode::TransferStalled { .. })) {
+        // waiting for either side would only stall again, dropping them
+        // kills them
+        return streamed.map(|()| total_bytes_sent);
+    }
+    drop(send_output);
+    drop(recv_input);
 
-    zfs_send
-        .wait()
+    // a stream usually breaks because one side exited, whose exit status and
+    // stderr say why
+    let send_result = timeout(inactivity, zfs_send.wait())
         .await
-        .map_err(|_| ErrorCode::FailedToWaitForZfsSend)?;
-    zfs_recv
-        .wait()
+        .map_err(|_| stalled())
+        .and_then(|waited| waited.map_err(|_| ErrorCode::FailedToWaitForZfsSend));
+    // `zfs recv` may still be writing out what it received
+    let recv_result = timeout(inactivity, zfs_recv.wait())
         .await
-        .map_err(|_| ErrorCode::FailedToWaitForZfsRecv)?;
-    Ok(())
+        .map_err(|_| stalled())
+        .and_then(|waited| waited.map_err(|_| ErrorCode::FailedToWaitForZfsRecv));
+
+    // a side that exited with an error says more than the other side losing
+    // its stream because of it
+    if let Some(send_output) = send_result.as_ref().ok().filter(|output| !output.success()) {
+        return Err(exec::failed(src_exec.server(), &send_line, send_output));
+    }
+    if let Some(recv_output) = recv_result.as_ref().ok().filter(|output| !output.success()) {
+        return Err(exec::failed(dst_exec.server(), &recv_line, recv_output));
+    }
+    send_result?;
+    recv_result?;
+    streamed.map(|()| total_bytes_sent)
 }
 
-pub async fn get_latest_snapshot(
-    session: &Session,
-    pool: &str,
-    dataset: &str,
-) -> Result<String, ErrorCode> {
-    let output = session
-        .command("zfs")
-        .arg("list")
-        .args(["-t", "snapshot"])
-        .args(["-o", "name"])
-        .args(["-S", "creation"])
-        .arg(format!("{pool}/{dataset}", pool = pool, dataset = dataset))
-        .output()
-        .await
-        .map_err(|_| ErrorCode::ZfsCommandError {
-            msg: format!("unable to list snapshots for {}/{}", pool, dataset),
-        })?;
+/// Summarises what changed in the filesystem since `snapshot`
+pub async fn zfs_diff(exec: &ExecutorRef, snapshot: &str) -> Result<DiffSummary, ErrorCode> {
+    let output = exec
+        .command(Operation::Diff {
+            snapshot: snapshot.to_owned(),
+        })
+        .long()
+        .checked_output()
+        .await?;
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let latest = stdout
-        .lines()
-        .nth(1)
-        .ok_or(ErrorCode::NoSnapshotsFound {
-            pool: pool.to_owned(),
-            dataset: dataset.to_owned(),
-        })?
-        .to_owned();
-    Ok(latest)
+    let mut summary = DiffSummary::default();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        match line.split('\t').next() {
+            Some("+") => summary.added += 1,
+            Some("-") => summary.removed += 1,
+            Some("M") => summary.modified += 1,
+            Some("R") => summary.renamed += 1,
+            _ => {}
+        }
+    }
+    Ok(summary)
 }
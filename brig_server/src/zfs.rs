@@ -0,0 +1,151 @@
+//! Typed zfs listings. Commands are run with `-H -p`, so their output is tab
+//! separated without a header and numbers are exact, and parsed into structs
+//! instead of being scraped.
+
+use std::str::FromStr;
+
+use brig_common::{agent::Operation, api::api::ErrorCode};
+use chrono::{DateTime, Utc};
+
+use crate::exec::ExecutorRef;
+
+/// A snapshot as listed by `zfs list -t snapshot`, with the properties in
+/// `agent::SNAPSHOT_PROPERTIES`
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    /// `pool/dataset@name`
+    pub name: String,
+    /// the same on every server the snapshot was received on
+    pub guid: u64,
+    /// unix timestamp
+    pub creation: i64,
+    pub used: u64,
+    pub referenced: u64,
+    pub written: u64,
+}
+
+/// The part of a snapshot after the `@`, which is the same on every server,
+/// for snapshots only known by name
+pub fn short_name(snapshot: &str) -> &str {
+    snapshot
+        .split_once('@')
+        .map(|(_, name)| name)
+        .unwrap_or(snapshot)
+}
+
+impl Snapshot {
+    /// The part after the `@`, which is the same on every server
+    pub fn short_name(&self) -> &str {
+        short_name(&self.name)
+    }
+
+    /// `pool/dataset`, or only `pool` for a snapshot of the pool's root
+    pub fn filesystem(&self) -> &str {
+        self.name
+            .split_once('@')
+            .map(|(filesystem, _)| filesystem)
+            .unwrap_or(&self.name)
+    }
+
+    pub fn pool(&self) -> &str {
+        let filesystem = self.filesystem();
+        filesystem
+            .split_once('/')
+            .map(|(pool, _)| pool)
+            .unwrap_or(filesystem)
+    }
+
+    /// The dataset below the pool, empty for the pool's root
+    pub fn dataset(&self) -> &str {
+        self.filesystem()
+            .split_once('/')
+            .map(|(_, dataset)| dataset)
+            .unwrap_or_default()
+    }
+
+    pub fn created(&self) -> Option<DateTime<Utc>> {
+        DateTime::from_timestamp(self.creation, 0)
+    }
+
+    fn parse(line: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [name, guid, creation, used, referenced, written] = fields[..] else {
+            return Err(format!("expected 6 fields, got {}", fields.len()));
+        };
+        if !name.contains('@') {
+            return Err(format!("`{}` is not a snapshot", name));
+        }
+        Ok(Self {
+            name: name.to_owned(),
+            guid: number("guid", guid)?,
+            creation: number("creation", creation)?,
+            used: number("used", used)?,
+            referenced: number("referenced", referenced)?,
+            // `-` for snapshots zfs can't compute it for
+            written: if written == "-" {
+                0
+            } else {
+                number("written", written)?
+            },
+        })
+    }
+}
+
+fn number<T: FromStr>(field: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} `{}` is not a number", field, value))
+}
+
+/// Lists the snapshots of `filesystem`, or of every pool, and parses every
+/// line of the output
+async fn list_snapshots(
+    exec: &ExecutorRef,
+    filesystem: Option<String>,
+) -> Result<Vec<Snapshot>, ErrorCode> {
+    let command = exec.command(Operation::ListSnapshots { filesystem }).long();
+    let command_line = command.line();
+    let output = command.checked_output().await?;
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            Snapshot::parse(line).map_err(|msg| ErrorCode::ZfsOutputParseError {
+                command: command_line.clone(),
+                line: line.to_owned(),
+                msg,
+            })
+        })
+        .collect()
+}
+
+/// Snapshots of `pool/dataset`, newest first
+pub async fn snapshots(
+    exec: &ExecutorRef,
+    pool: &str,
+    dataset: &str,
+) -> Result<Vec<Snapshot>, ErrorCode> {
+    list_snapshots(exec, Some(format!("{}/{}", pool, dataset))).await
+}
+
+/// The newest snapshot of `pool/dataset`
+pub async fn latest_snapshot(
+    exec: &ExecutorRef,
+    pool: &str,
+    dataset: &str,
+) -> Result<Snapshot, ErrorCode> {
+    snapshots(exec, pool, dataset)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or(ErrorCode::NoSnapshotsFound {
+            pool: pool.to_owned(),
+            dataset: dataset.to_owned(),
+        })
+}
+
+/// Every snapshot on the server, in every pool
+pub async fn all_snapshots(exec: &ExecutorRef) -> Result<Vec<Snapshot>, ErrorCode> {
+    list_snapshots(exec, None).await
+}